@@ -1,73 +1,356 @@
-use std::{fmt::Display, time::Duration};
+use std::{
+    fmt::Display,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use futures::{stream::unfold, StreamExt as _};
+use procfs::{process::Process, Current as _, Meminfo};
 use tokio::{
-    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, ErrorKind},
     net::UnixStream as TokioUnixStream,
-    pin,
     time::Instant,
 };
 
-use crate::config::{Field, Format, Scale, Source};
+use crate::{
+    config::{Config, Field, Format, Output, Scale, Source},
+    logfile::LogFile,
+    protocol::{
+        Hello, Request, Response, Root, TreeState, TreeStats, CAPABILITY_PSS, PROTOCOL_VERSION,
+    },
+};
+
+/// The maximum time to wait for the daemon to respond to the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An error which ended the client.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The daemon is incompatible with or older than this client, and should be replaced.
+    StaleDaemon {
+        /// The PID of the daemon, if known.
+        pid: Option<i32>,
+        /// The reason for considering the daemon stale.
+        reason: String,
+    },
+    /// Any other error.
+    Other(String),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::StaleDaemon { reason, .. } => write!(f, "{reason}"),
+            ClientError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<String> for ClientError {
+    fn from(e: String) -> Self {
+        ClientError::Other(e)
+    }
+}
+
+impl From<&str> for ClientError {
+    fn from(e: &str) -> Self {
+        ClientError::Other(e.to_owned())
+    }
+}
+
+/// The reason the client has finished.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The timeout has passed or the daemon has disconnected.
+    Finished,
+    /// All monitored process trees are gone and [Config::exit_when_gone] is set.
+    AllGone,
+}
 
 /// Run the client for as long as configured.
+///
+/// If `replace_stale` is set, a daemon of a different version than this client is reported as
+/// [ClientError::StaleDaemon] even if it can serve the client. An incompatible daemon is always
+/// reported that way, but a compatible one lacking a capability needed by this client is not.
 pub async fn run(
     mut stream: TokioUnixStream,
-    pids: Vec<i32>,
-    timeout: Option<Duration>,
-    fields: Vec<Field>,
-    separator: String,
-) -> Result<(), String> {
-    for pid in &pids {
-        stream
-            .write_i32(*pid)
-            .await
-            .map_err(|e| format!("error writing to server: {e}"))?;
-    }
-    stream
-        .flush()
-        .await
-        .map_err(|e| format!("error flushing stream: {e}"))?;
-    stream
-        .shutdown()
+    config: &Config,
+    replace_stale: bool,
+) -> Result<Outcome, ClientError> {
+    let daemon_pid = stream.peer_cred().ok().and_then(|cred| cred.pid());
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut capabilities = vec![];
+    if config.needs_pss() {
+        capabilities.push(CAPABILITY_PSS.to_owned());
+    }
+    let hello = handshake(&mut reader, &mut writer, &capabilities)
         .await
-        .map_err(|e| format!("error shutting down stream: {e}"))?;
-    let loads_stream = unfold(stream, |mut stream| async {
-        stream.read_f32().await.ok().map(|load| (load, stream))
-    })
-    .chunks(pids.len());
-    pin!(loads_stream);
-    let deadline = timeout.map(|tmout| Instant::now() + tmout);
-    while let Some(loads) = loads_stream.next().await {
-        println!(
-            "{}",
-            OutputLine(&fields, &separator, num_cpus::get(), loads)
-        );
+        .map_err(|reason| ClientError::StaleDaemon {
+            pid: daemon_pid,
+            reason,
+        })?;
+    if let Some(reason) = replace_stale
+        .then(|| staleness(&hello, daemon_pid))
+        .flatten()
+    {
+        Err(ClientError::StaleDaemon {
+            pid: daemon_pid,
+            reason,
+        })?
+    }
+    // Not a reason to replace the daemon, which may be serving other clients.
+    if let Some(missing) = capabilities
+        .iter()
+        .find(|c| !hello.capabilities.contains(c))
+    {
+        Err(format!(
+            "daemon lacks capability `{missing}`, it was spawned by a client that did not need it, \
+             restart it with `pidtree_mon daemon restart` and the same options"
+        ))?
+    }
+    // Processes which are not running right now are accepted whenever they appear.
+    let roots: Vec<_> = config
+        .pids
+        .iter()
+        .map(|&pid| Root {
+            pid,
+            start_time: Process::new(pid)
+                .and_then(|p| p.stat())
+                .ok()
+                .map(|stat| stat.starttime),
+        })
+        .collect();
+    let selectors = config.selectors();
+    let trees_count = roots.len() + selectors.len();
+    Request::Monitor {
+        roots,
+        selectors,
+        interval: config.interval,
+        // the lines are meant to be real measurements over the interval
+        wait_full: config.line_count().is_some(),
+    }
+    .write_to(&mut writer)
+    .await
+    .map_err(|e| format!("error writing to server: {e}"))?;
+    let mut printer = Printer::new(config, Capacity::current())?;
+    let deadline = config.timeout.map(|tmout| Instant::now() + tmout);
+    loop {
+        let trees = match Response::read_from(&mut reader).await {
+            Ok(Response::Update(trees)) => trees,
+            Ok(Response::Error(e)) => Err(format!("daemon error: {e}"))?,
+            Ok(response) => Err(format!("unexpected response from server: {response:?}"))?,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => Err(format!("error reading from server: {e}"))?,
+        };
+        if trees.len() != trees_count {
+            Err("server sent statistics of a wrong number of process trees")?
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if let Some(outcome) = printer.print(timestamp, &trees)? {
+            return Ok(outcome);
+        }
         if deadline.is_some_and(|d| Instant::now() > d) {
             break;
         }
     }
-    Ok(())
+    Ok(Outcome::Finished)
+}
+
+/// Prints the statistics of each update as configured, and to the log file, if any.
+pub struct Printer<'a> {
+    config: &'a Config,
+    capacity: Capacity,
+    /// The names of the process trees, see [Config::labels]
+    labels: Vec<String>,
+    log_file: Option<LogFile>,
+    lines_left: Option<u64>,
+    /// Whether each process tree has been alive in any update, see [Config::exit_when_gone]
+    seen_alive: Vec<bool>,
+}
+
+impl<'a> Printer<'a> {
+    /// Open the log file, if any, and print the header of the output, if needed.
+    pub fn new(config: &'a Config, capacity: Capacity) -> Result<Self, String> {
+        let labels = config.labels();
+        let log_file = LogFile::open(config, &labels)?;
+        if config.output == Output::I3bar {
+            // the header, followed by an infinite array of status lines
+            println!("{{\"version\":1}}");
+            println!("[");
+        }
+        Ok(Self {
+            config,
+            capacity,
+            seen_alive: vec![false; labels.len()],
+            labels,
+            log_file,
+            lines_left: config.line_count(),
+        })
+    }
+
+    /// Print the statistics of each process tree, as of the UNIX `timestamp`.
+    ///
+    /// Returns the reason to finish if no more updates should be printed.
+    pub fn print(
+        &mut self,
+        timestamp: f64,
+        trees: &[TreeState],
+    ) -> Result<Option<Outcome>, String> {
+        let Printer {
+            config,
+            capacity,
+            labels,
+            ..
+        } = self;
+        if let Some(log_file) = &mut self.log_file {
+            log_file.write(timestamp, trees)?;
+        }
+        match config.output {
+            Output::Text => {
+                let stats = trees.iter().map(TreeState::stats).collect();
+                let line = OutputLine(
+                    &config.fields,
+                    &config.separator,
+                    &config.missing,
+                    capacity,
+                    stats,
+                );
+                println!("{line}");
+            }
+            Output::Json => {
+                println!("{}", JsonLine(timestamp, labels, capacity, trees));
+            }
+            Output::I3bar | Output::Waybar | Output::Polybar => {
+                println!("{}", BarLine(config, labels, capacity, trees));
+            }
+        }
+        for (seen, tree) in self.seen_alive.iter_mut().zip(trees) {
+            *seen |= *tree != TreeState::Gone;
+        }
+        // A tree which has not been alive yet, e.g. a selector not matching anything, may still
+        // appear.
+        let all_gone = trees
+            .iter()
+            .zip(&self.seen_alive)
+            .all(|(tree, seen)| *seen && *tree == TreeState::Gone);
+        if config.exit_when_gone && all_gone {
+            return Ok(Some(Outcome::AllGone));
+        }
+        if let Some(lines) = &mut self.lines_left {
+            *lines -= 1;
+            if *lines == 0 {
+                return Ok(Some(Outcome::Finished));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Exchange hellos with the daemon and make sure it speaks the protocol of this client.
+pub async fn handshake(
+    reader: impl AsyncReadExt + Unpin,
+    writer: impl AsyncWriteExt + Unpin,
+    capabilities: &[String],
+) -> Result<Hello, String> {
+    Hello::new(capabilities.to_vec())
+        .write_to(writer)
+        .await
+        .map_err(|e| format!("error writing to server: {e}"))?;
+    let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, Hello::read_from(reader))
+        .await
+        .map_err(|_| {
+            "daemon did not respond to handshake, it is probably an older, incompatible version"
+        })?
+        .map_err(|e| format!("error reading handshake, incompatible daemon? {e}"))?;
+    if !hello.is_compatible() {
+        Err(format!(
+            "incompatible daemon version {} (protocol version {}, expected {})",
+            hello.package_version, hello.version, PROTOCOL_VERSION
+        ))?
+    }
+    Ok(hello)
+}
+
+/// Check if a compatible daemon is nevertheless outdated, and if so, return the reason.
+///
+/// A daemon is outdated if it is of a different version, or if it runs from an executable that has
+/// been since replaced with this client's executable.
+fn staleness(hello: &Hello, daemon_pid: Option<i32>) -> Option<String> {
+    let own_version = env!("CARGO_PKG_VERSION");
+    if hello.package_version != own_version {
+        return Some(format!(
+            "daemon version {} differs from client version {own_version}",
+            hello.package_version
+        ));
+    }
+    let daemon_exe = std::fs::read_link(format!("/proc/{}/exe", daemon_pid?)).ok()?;
+    let own_exe = std::env::current_exe().ok()?;
+    let replaced = daemon_exe
+        .to_str()?
+        .strip_suffix(" (deleted)")
+        .is_some_and(|daemon_exe| Path::new(daemon_exe) == own_exe);
+    replaced.then(|| format!("daemon executable {} was replaced", own_exe.display()))
+}
+
+/// The total resources of the system, used as base values for [Scale::OfTotal].
+pub struct Capacity {
+    /// The number of cores
+    pub cores: f32,
+    /// The total memory in bytes
+    pub memory: f32,
+}
+
+impl Capacity {
+    /// The total resources of the current system.
+    pub fn current() -> Self {
+        Self {
+            cores: num_cpus::get() as f32,
+            memory: Meminfo::current()
+                .map(|m| m.mem_total as f32)
+                .unwrap_or(f32::NAN),
+        }
+    }
 }
 
-struct OutputLine<'a>(&'a Vec<Field>, &'a str, usize, Vec<f32>);
+/// A line of output: the fields, the separator, the default placeholder of unknown values, the
+/// total resources of the system and the statistics of each process tree.
+struct OutputLine<'a>(
+    &'a Vec<Field>,
+    &'a str,
+    &'a str,
+    &'a Capacity,
+    Vec<TreeStats>,
+);
 
 impl<'f> Display for OutputLine<'f> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let OutputLine(spec, sep, num_cores, loads) = self;
-        let sum: f32 = loads
-            .iter()
-            .map(|l| if l.is_nan() { 0.0 } else { *l })
-            .sum();
+        let OutputLine(spec, sep, missing, capacity, stats) = self;
         let mut any_written = false;
-        for Field(source, scale, format) in spec.iter() {
+        for Field(source, scale, format, placeholder) in spec.iter() {
+            let placeholder = placeholder.as_deref().unwrap_or(missing);
+            let (metric, base): (fn(&TreeStats) -> f32, _) = match source {
+                Source::Sum | Source::AllLoads => (|s| s.load, capacity.cores),
+                Source::Lifetime | Source::AllLifetime => (|s| s.lifetime, capacity.cores),
+                Source::Mem | Source::AllMem => (|s| s.rss, capacity.memory),
+                Source::Pss | Source::AllPss => (|s| s.pss, capacity.memory),
+                Source::IoRead | Source::AllIoRead => (|s| s.io_read, f32::NAN),
+                Source::IoWrite | Source::AllIoWrite => (|s| s.io_write, f32::NAN),
+                Source::Procs | Source::AllProcs => (|s| s.procs, f32::NAN),
+                Source::Threads | Source::AllThreads => (|s| s.threads, f32::NAN),
+                Source::Hidden | Source::AllHidden => (|s| s.hidden, f32::NAN),
+            };
             let scale = match scale {
                 Scale::OfCore => 1.0,
-                Scale::OfTotal => *num_cores as f32,
+                Scale::OfTotal => base,
             };
-            let inputs = match source {
-                Source::Sum => &vec![sum],
-                Source::AllLoads => loads,
+            let values: Vec<f32> = stats.iter().map(metric).collect();
+            let inputs = if source.is_per_tree() {
+                values
+            } else {
+                vec![sum(&values)]
             };
             let inputs: Vec<f32> = inputs.iter().map(|i| i / scale).collect();
             for input in inputs {
@@ -75,6 +358,14 @@ impl<'f> Display for OutputLine<'f> {
                     write!(f, "{sep}")?;
                 }
                 match format {
+                    Format::Float(_)
+                    | Format::Percent(_)
+                    | Format::Bytes(_)
+                    | Format::ByteRate(_)
+                        if input.is_nan() =>
+                    {
+                        write!(f, "{placeholder}")?
+                    }
                     Format::Float(precision) | Format::Percent(precision) => {
                         let mul = match format {
                             Format::Float(_) => 1.0,
@@ -83,6 +374,10 @@ impl<'f> Display for OutputLine<'f> {
                         };
                         write!(f, "{:.1$}", input * mul, *precision as usize)?
                     }
+                    Format::Bytes(precision) => write!(f, "{}", HumanBytes(input, *precision))?,
+                    Format::ByteRate(precision) => {
+                        write!(f, "{}/s", HumanBytes(input, *precision))?
+                    }
                     Format::IfThenElse {
                         test,
                         then,
@@ -102,12 +397,246 @@ impl<'f> Display for OutputLine<'f> {
     }
 }
 
+/// A line of JSON output: the UNIX timestamp, the names of the process trees, the total resources
+/// of the system and the state of each process tree.
+struct JsonLine<'a>(f64, &'a [String], &'a Capacity, &'a [TreeState]);
+
+impl Display for JsonLine<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let JsonLine(timestamp, labels, capacity, trees) = self;
+        write!(f, "{{\"timestamp\":{timestamp:.3}")?;
+        write!(f, ",\"cores\":{}", JsonNumber(capacity.cores))?;
+        write!(f, ",\"memory\":{}", JsonNumber(capacity.memory))?;
+        write!(f, ",\"trees\":{{")?;
+        for (i, (label, tree)) in labels.iter().zip(trees.iter()).enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:", JsonString(label))?;
+            match tree {
+                TreeState::Alive(stats) => write!(f, "{}", JsonStats(stats))?,
+                TreeState::Gone => write!(f, "null")?,
+            }
+        }
+        let stats: Vec<_> = trees.iter().map(TreeState::stats).collect();
+        let sum_of =
+            |metric: fn(&TreeStats) -> f32| sum(&stats.iter().map(metric).collect::<Vec<_>>());
+        let sums = TreeStats {
+            load: sum_of(|s| s.load),
+            lifetime: sum_of(|s| s.lifetime),
+            rss: sum_of(|s| s.rss),
+            pss: sum_of(|s| s.pss),
+            io_read: sum_of(|s| s.io_read),
+            io_write: sum_of(|s| s.io_write),
+            procs: sum_of(|s| s.procs),
+            threads: sum_of(|s| s.threads),
+            hidden: sum_of(|s| s.hidden),
+        };
+        write!(f, "}},\"sum\":{}}}", JsonStats(&sums))
+    }
+}
+
+/// Statistics of a process tree as a JSON object.
+struct JsonStats<'a>(&'a TreeStats);
+
+impl Display for JsonStats<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = self.0;
+        write!(f, "{{\"load\":{}", JsonNumber(s.load))?;
+        write!(f, ",\"lifetime\":{}", JsonNumber(s.lifetime))?;
+        write!(f, ",\"rss\":{}", JsonNumber(s.rss))?;
+        write!(f, ",\"pss\":{}", JsonNumber(s.pss))?;
+        write!(f, ",\"io_read\":{}", JsonNumber(s.io_read))?;
+        write!(f, ",\"io_write\":{}", JsonNumber(s.io_write))?;
+        write!(f, ",\"procs\":{}", JsonNumber(s.procs))?;
+        write!(f, ",\"threads\":{}", JsonNumber(s.threads))?;
+        write!(f, ",\"hidden\":{}}}", JsonNumber(s.hidden))
+    }
+}
+
+/// A line of output for a status bar, in the format determined by [Config::output]: the
+/// configuration, the names of the process trees, the total resources of the system and the state
+/// of each process tree.
+struct BarLine<'a>(&'a Config, &'a [String], &'a Capacity, &'a [TreeState]);
+
+impl BarLine<'_> {
+    /// Render fields as they would be printed by the text output.
+    fn render(&self, fields: Vec<Field>, separator: &str) -> String {
+        let BarLine(config, _, capacity, trees) = self;
+        let stats = trees.iter().map(TreeState::stats).collect();
+        OutputLine(&fields, separator, &config.missing, capacity, stats).to_string()
+    }
+
+    fn color(&self) -> Option<String> {
+        let color = self.render(self.0.color.iter().cloned().collect(), "");
+        (!color.is_empty()).then_some(color)
+    }
+
+    /// A breakdown of the most important statistics of each process tree.
+    fn tooltip(&self) -> String {
+        let BarLine(_, labels, _, trees) = self;
+        let lines: Vec<_> = labels
+            .iter()
+            .zip(trees.iter())
+            .map(|(label, tree)| match tree {
+                TreeState::Alive(s) if s.hidden > 0.0 => format!(
+                    "{label}: load {:.2}, memory {}, {} processes, {} hidden",
+                    s.load,
+                    HumanBytes(s.rss, 1),
+                    s.procs,
+                    s.hidden
+                ),
+                TreeState::Alive(s) => format!(
+                    "{label}: load {:.2}, memory {}, {} processes",
+                    s.load,
+                    HumanBytes(s.rss, 1),
+                    s.procs
+                ),
+                TreeState::Gone => format!("{label}: gone"),
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+impl Display for BarLine<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let BarLine(config, _, capacity, trees) = self;
+        let text = self.render(config.fields.clone(), &config.separator);
+        match config.output {
+            Output::I3bar => {
+                write!(
+                    f,
+                    "[{{\"name\":\"pidtree_mon\",\"full_text\":{}",
+                    JsonString(&text)
+                )?;
+                if let Some(color) = self.color() {
+                    write!(f, ",\"color\":{}", JsonString(&color))?;
+                }
+                write!(f, "}}],")
+            }
+            Output::Waybar => {
+                write!(f, "{{\"text\":{}", JsonString(&text))?;
+                write!(f, ",\"tooltip\":{}", JsonString(&self.tooltip()))?;
+                let classes = config
+                    .classes
+                    .iter()
+                    .flat_map(|field| {
+                        let class = self.render(vec![field.clone()], " ");
+                        class
+                            .split_whitespace()
+                            .map(str::to_owned)
+                            .collect::<Vec<_>>()
+                    })
+                    .map(|class| JsonString(&class).to_string())
+                    .collect::<Vec<_>>();
+                write!(f, ",\"class\":[{}]", classes.join(","))?;
+                let loads: Vec<_> = trees.iter().map(|tree| tree.stats().load).collect();
+                let percentage = sum(&loads) / capacity.cores * 100.0;
+                if percentage.is_finite() {
+                    write!(f, ",\"percentage\":{:.0}", percentage.clamp(0.0, 100.0))?;
+                }
+                write!(f, "}}")
+            }
+            Output::Polybar => match self.color() {
+                Some(color) => write!(f, "%{{F{color}}}{text}%{{F-}}"),
+                None => write!(f, "{text}"),
+            },
+            Output::Text | Output::Json => unreachable!("not a status bar output"),
+        }
+    }
+}
+
+/// A string in JSON, quoted and escaped.
+struct JsonString<'a>(&'a str);
+
+impl Display for JsonString<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// A number in JSON, which has no representation of unknown or infinite values, so they are
+/// printed as `null`.
+struct JsonNumber(f32);
+
+impl Display for JsonNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            v if v.is_finite() => write!(f, "{v}"),
+            _ => write!(f, "null"),
+        }
+    }
+}
+
+/// Sum values, excluding unknown ones, or return an unknown value if there are values and all of
+/// them are unknown.
+///
+/// The sum of no values at all is zero.
+pub fn sum(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values
+        .iter()
+        .filter(|v| !v.is_nan())
+        .copied()
+        .reduce(|acc, v| acc + v)
+        .unwrap_or(f32::NAN)
+}
+
+/// A size in bytes printed using binary units with a certain precision.
+struct HumanBytes(f32, u8);
+
+impl Display for HumanBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const UNITS: [&str; 6] = ["B", "K", "M", "G", "T", "P"];
+        let HumanBytes(mut value, precision) = *self;
+        if !value.is_finite() {
+            return write!(f, "{value}");
+        }
+        let mut unit = 0;
+        while value.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        // fractions of a byte make no sense
+        let precision = if unit == 0 { 0 } else { precision as usize };
+        write!(f, "{:.1$}{2}", value, precision, UNITS[unit])
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::config::Test;
 
     use super::*;
 
+    const CAPACITY: Capacity = Capacity {
+        cores: 3.0,
+        memory: 4096.0,
+    };
+
+    fn loads(loads: &[f32]) -> Vec<TreeStats> {
+        loads
+            .iter()
+            .map(|load| TreeStats {
+                load: *load,
+                ..TreeStats::MISSING
+            })
+            .collect()
+    }
+
     #[test]
     fn test() {
         let fields = vec![
@@ -119,6 +648,7 @@ mod tests {
                     then: "x".to_owned(),
                     otherwise: "y".to_owned(),
                 },
+                None,
             ),
             Field(
                 Source::AllLoads,
@@ -128,14 +658,305 @@ mod tests {
                     then: "x".to_owned(),
                     otherwise: "y".to_owned(),
                 },
+                None,
             ),
-            Field(Source::Sum, Scale::OfTotal, Format::Float(3)),
+            Field(Source::Sum, Scale::OfTotal, Format::Float(3), None),
         ];
-        let o = OutputLine(&fields, " ", 3, vec![0.5, 2.0, 3.5]);
+        let o = OutputLine(&fields, " ", "NaN", &CAPACITY, loads(&[0.5, 2.0, 3.5]));
         assert_eq!(o.to_string(), "x x y y 2.000");
-        let o = OutputLine(&fields, "", 3, vec![0.0, 0.0, 1.5]);
+        let o = OutputLine(&fields, "", "NaN", &CAPACITY, loads(&[0.0, 0.0, 1.5]));
         assert_eq!(o.to_string(), "xxxy0.500");
-        let o = OutputLine(&fields, "xxx", 3, vec![]);
+        let o = OutputLine(&fields, "xxx", "-", &CAPACITY, loads(&[]));
         assert_eq!(o.to_string(), "yxxx0.000");
+        let o = OutputLine(&fields, "xxx", "-", &CAPACITY, loads(&[f32::NAN]));
+        assert_eq!(o.to_string(), "yxxxyxxx-");
+    }
+
+    #[test]
+    fn test_mem() {
+        let fields = vec![
+            Field(Source::Mem, Scale::OfCore, Format::Bytes(1), None),
+            Field(Source::AllMem, Scale::OfCore, Format::Bytes(0), None),
+            Field(Source::Mem, Scale::OfTotal, Format::Percent(0), None),
+            Field(Source::Pss, Scale::OfCore, Format::Bytes(1), None),
+        ];
+        let stats = vec![
+            TreeStats {
+                load: 0.0,
+                lifetime: 0.0,
+                rss: 1536.0,
+                pss: f32::NAN,
+                io_read: 0.0,
+                io_write: 0.0,
+                procs: 1.0,
+                threads: 1.0,
+                hidden: 0.0,
+            },
+            TreeStats {
+                load: 0.0,
+                lifetime: 0.0,
+                rss: 512.0,
+                pss: f32::NAN,
+                io_read: 0.0,
+                io_write: 0.0,
+                procs: 1.0,
+                threads: 1.0,
+                hidden: 0.0,
+            },
+            TreeStats::MISSING,
+        ];
+        let o = OutputLine(&fields, " ", "NaN", &CAPACITY, stats);
+        assert_eq!(o.to_string(), "2.0K 2K 512B NaN 50 NaN");
+    }
+
+    #[test]
+    fn test_io() {
+        let fields = vec![
+            Field(Source::IoRead, Scale::OfCore, Format::ByteRate(1), None),
+            Field(Source::AllIoWrite, Scale::OfCore, Format::ByteRate(0), None),
+            Field(
+                Source::IoWrite,
+                Scale::OfCore,
+                Format::IfThenElse {
+                    test: Test::Range(Some(1024.0 * 1024.0), None),
+                    then: "busy".to_owned(),
+                    otherwise: "idle".to_owned(),
+                },
+                None,
+            ),
+        ];
+        let stats = vec![
+            TreeStats {
+                io_read: 2048.0,
+                io_write: 4.0 * 1024.0 * 1024.0,
+                ..TreeStats::MISSING
+            },
+            TreeStats {
+                io_read: 1024.0,
+                io_write: 0.0,
+                ..TreeStats::MISSING
+            },
+        ];
+        let o = OutputLine(&fields, " ", "NaN", &CAPACITY, stats);
+        assert_eq!(o.to_string(), "3.0K/s 4M/s 0B/s busy");
+    }
+
+    #[test]
+    fn test_counts() {
+        let fields = vec![
+            Field(Source::Procs, Scale::OfCore, Format::Float(0), None),
+            Field(Source::AllThreads, Scale::OfCore, Format::Float(0), None),
+            Field(
+                Source::Procs,
+                Scale::OfCore,
+                Format::IfThenElse {
+                    test: Test::Range(Some(100.0), None),
+                    then: "forkbomb".to_owned(),
+                    otherwise: "ok".to_owned(),
+                },
+                None,
+            ),
+        ];
+        let stats = vec![
+            TreeStats {
+                procs: 3.0,
+                threads: 10.0,
+                ..TreeStats::MISSING
+            },
+            TreeStats {
+                procs: 120.0,
+                threads: 120.0,
+                ..TreeStats::MISSING
+            },
+        ];
+        let o = OutputLine(&fields, " ", "NaN", &CAPACITY, stats);
+        assert_eq!(o.to_string(), "123 10 120 forkbomb");
+    }
+
+    #[test]
+    fn test_hidden() {
+        let fields = vec![
+            Field(Source::AllLoads, Scale::OfCore, Format::Float(1), None),
+            Field(
+                Source::AllHidden,
+                Scale::OfCore,
+                Format::IfThenElse {
+                    test: Test::Range(Some(1.0), None),
+                    then: "(incomplete)".to_owned(),
+                    otherwise: "".to_owned(),
+                },
+                None,
+            ),
+            Field(Source::Hidden, Scale::OfCore, Format::Float(0), None),
+        ];
+        let stats = vec![
+            TreeStats {
+                load: 0.5,
+                hidden: 0.0,
+                ..TreeStats::MISSING
+            },
+            TreeStats {
+                load: 1.5,
+                hidden: 2.0,
+                ..TreeStats::MISSING
+            },
+            TreeStats::MISSING,
+        ];
+        let o = OutputLine(&fields, " ", "-", &CAPACITY, stats);
+        assert_eq!(o.to_string(), "0.5 1.5 -  (incomplete)  2");
+    }
+
+    #[test]
+    fn test_missing() {
+        let fields = vec![
+            Field(Source::Sum, Scale::OfCore, Format::Float(1), None),
+            Field(Source::AllLoads, Scale::OfCore, Format::Float(1), None),
+            Field(
+                Source::AllMem,
+                Scale::OfCore,
+                Format::Bytes(1),
+                Some("".to_owned()),
+            ),
+            Field(
+                Source::AllLoads,
+                Scale::OfCore,
+                Format::IfThenElse {
+                    test: Test::Missing,
+                    then: "gone".to_owned(),
+                    otherwise: "ok".to_owned(),
+                },
+                None,
+            ),
+        ];
+        let stats = vec![
+            TreeStats {
+                load: 1.5,
+                rss: 1024.0,
+                ..TreeStats::MISSING
+            },
+            TreeStats::MISSING,
+        ];
+        let o = OutputLine(&fields, " ", "-", &CAPACITY, stats);
+        assert_eq!(o.to_string(), "1.5 1.5 - 1.0K  ok gone");
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let fields = vec![
+            Field(Source::Lifetime, Scale::OfCore, Format::Float(2), None),
+            Field(
+                Source::AllLifetime,
+                Scale::OfTotal,
+                Format::Percent(0),
+                None,
+            ),
+        ];
+        let stats = vec![
+            TreeStats {
+                lifetime: 0.5,
+                ..TreeStats::MISSING
+            },
+            TreeStats {
+                lifetime: 2.0,
+                ..TreeStats::MISSING
+            },
+        ];
+        let o = OutputLine(&fields, " ", "NaN", &CAPACITY, stats);
+        assert_eq!(o.to_string(), "2.50 17 67");
+    }
+
+    #[test]
+    fn test_json() {
+        let trees = [
+            TreeState::Alive(TreeStats {
+                load: 1.5,
+                lifetime: 0.25,
+                rss: 1024.0,
+                pss: f32::NAN,
+                io_read: 0.0,
+                io_write: 512.0,
+                procs: 2.0,
+                threads: 3.0,
+                hidden: 0.0,
+            }),
+            TreeState::Gone,
+        ];
+        let o = JsonLine(
+            1700000000.5,
+            &["10".to_owned(), "20".to_owned()],
+            &CAPACITY,
+            &trees,
+        );
+        assert_eq!(
+            o.to_string(),
+            concat!(
+                r#"{"timestamp":1700000000.500,"cores":3,"memory":4096,"trees":{"#,
+                r#""10":{"load":1.5,"lifetime":0.25,"rss":1024,"pss":null,"io_read":0,"#,
+                r#""io_write":512,"procs":2,"threads":3,"hidden":0},"20":null},"#,
+                r#""sum":{"load":1.5,"lifetime":0.25,"rss":1024,"pss":null,"io_read":0,"#,
+                r#""io_write":512,"procs":2,"threads":3,"hidden":0}}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_bars() {
+        use clap::Parser as _;
+
+        let trees = [
+            TreeState::Alive(TreeStats {
+                load: 1.5,
+                rss: 1024.0,
+                procs: 2.0,
+                ..TreeStats::MISSING
+            }),
+            TreeState::Gone,
+        ];
+        let bar = |output| {
+            let config = Config::try_parse_from([
+                "pidtree_mon",
+                "-o",
+                output,
+                "-f",
+                "sum:.1",
+                "-f",
+                "all_loads:if_missing:\"gone\"",
+                "--color",
+                "sum:if_range:1..:#ff0000",
+                "--class",
+                "all_loads:if_range:1..:busy:idle",
+                "--class",
+                "sum:if_range:..1:idle",
+                "10",
+                "20",
+            ])
+            .unwrap();
+            BarLine(&config, &config.labels(), &CAPACITY, &trees).to_string()
+        };
+        assert_eq!(
+            bar("i3bar"),
+            r##"[{"name":"pidtree_mon","full_text":"1.5  \"gone\"","color":"#ff0000"}],"##
+        );
+        assert_eq!(
+            bar("waybar"),
+            concat!(
+                r#"{"text":"1.5  \"gone\"","#,
+                r#""tooltip":"10: load 1.50, memory 1.0K, 2 processes\n20: gone","#,
+                r#""class":["busy","idle"],"percentage":50}"#
+            )
+        );
+        assert_eq!(bar("polybar"), "%{F#ff0000}1.5  \"gone\"%{F-}");
+    }
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!(HumanBytes(0.0, 1).to_string(), "0B");
+        assert_eq!(HumanBytes(1023.0, 1).to_string(), "1023B");
+        assert_eq!(HumanBytes(1024.0, 1).to_string(), "1.0K");
+        assert_eq!(
+            HumanBytes(3.5 * 1024.0 * 1024.0 * 1024.0, 2).to_string(),
+            "3.50G"
+        );
+        assert_eq!(HumanBytes(f32::NAN, 1).to_string(), "NaN");
     }
 }
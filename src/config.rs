@@ -1,44 +1,110 @@
-use std::{ops::Not as _, str::FromStr, time::Duration};
+use std::{ops::Not as _, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::protocol::Selector;
 
 /// Application configuration
 #[derive(clap::Parser, Debug)]
-#[command(version, about, long_about = None, after_help = concat!(
+#[command(version, about, long_about = None, subcommand_negates_reqs = true, after_help = concat!(
     "Explanation of fields\n",
     "\n",
     "Multiple fields can be passed via -f/--field. A basic field can be:\n",
     " * `sum' - sum of loads of all provided process trees,\n",
-    " * `all_loads' - produces multiple fields, one for each process tree.\n",
+    " * `all_loads' - produces multiple fields, one for each process tree,\n",
+    " * `lifetime', `all_lifetime' - like `sum' and `all_loads', but the average load since\n",
+    "   the root process of each tree started,\n",
+    " * `mem' - sum of resident memory (RSS) of all provided process trees,\n",
+    " * `all_mem' - produces multiple fields, RSS of each process tree,\n",
+    " * `pss', `all_pss' - like `mem' and `all_mem', but using proportional set size (PSS),\n",
+    "   which is more expensive to measure and only done by a daemon spawned by a client\n",
+    "   using one of these fields,\n",
+    " * `io_read', `io_write' - sum of storage read/write rates of all provided process trees,\n",
+    " * `all_io_read', `all_io_write' - produces multiple fields, one for each process tree,\n",
+    " * `procs', `threads' - total number of processes/threads in all provided process trees,\n",
+    " * `all_procs', `all_threads' - produces multiple fields, one for each process tree,\n",
+    " * `hidden', `all_hidden' - number of processes in the process trees which cannot be\n",
+    "   read because /proc is mounted with hidepid, making the other values incomplete.\n",
     "\n",
-    "The values are scaled per-core, so n means n whole cores are being used.\n",
-    "Adding `_t' to either field scales the loads according to the total computing power,\n",
-    "1 being the maximum.\n",
+    "The loads are scaled per-core, so n means n whole cores are being used.\n",
+    "Adding `_t' to a load or memory field scales the values according to the total\n",
+    "computing power or the total memory, 1 being the maximum.\n",
     "\n",
     "A format specifier can be added after colon:\n",
     " * .N - prints with N digits after decimal point,\n",
     " * %N - prints with N digits after decimal point, scaled up by a factor of 100,\n",
+    " * bN - prints a size in bytes using binary units (K, M, G, ...) with N digits after\n",
+    "        decimal point, the default for memory fields,\n",
+    " * rN - like bN, but prints a rate in bytes per second, the default for I/O fields,\n",
     " * if_range:[L]..[H]:then[:else] - produces `then` if field value is between `L' and `H',\n",
     "                                   `else` otherwise, `L`, `H` and `else` are optional,\n",
     " * if_greater:thr:then[:else]    - like if_range, but field value must be greater than `thr`,\n",
     "                                   DEPRECATED\n",
+    " * if_missing:then[:else]        - produces `then` if field value is unknown, `else` otherwise.\n",
+    "\n",
+    "Additionally, the last three specifiers can be used alone, without a preceding value,\n",
+    "in this case, the value defaults to `sum`.\n",
     "\n",
-    "Additionally, the last two specifiers can be used alone, without a preceding value,\n",
-    "in this case, the value defaults to `sum`.",
+    "Values are unknown if a process tree is gone, or if they cannot be measured. Such\n",
+    "values are excluded from sums, which are unknown only if all the values are. Unknown\n",
+    "values are printed as the placeholder given by --missing, which can be overridden for\n",
+    "a field by adding it after the format specifier, e.g. `all_loads:.2:-'.",
 ))]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// The collection of PIDs to monitor.
-    #[arg(name = "pid", required = true, num_args = 1..)]
+    #[arg(
+        name = "pid",
+        required_unless_present_any = ["comm", "cmdline_regex", "exe"],
+        num_args = 1..
+    )]
     pub pids: Vec<i32>,
+    /// Also monitor the processes with this command name, as shown by `ps -o comm`.
+    ///
+    /// Each selector is reported like an additional PID, after the PIDs, taking together the trees
+    /// of all matching processes which are not descendants of other matching ones. Selectors are
+    /// resolved on every sample, so processes started later are picked up. Can be given multiple
+    /// times.
+    ///
+    /// With --replay, --cmdline-regex and --exe only match the processes whose command line and
+    /// executable were recorded, which only happens while a client selects processes that way.
+    #[arg(long, value_name = "NAME")]
+    pub comm: Vec<String>,
+    /// Also monitor the processes whose command line, with arguments separated by spaces, matches
+    /// this regular expression, like `pgrep -f`.
+    ///
+    /// See --comm. Processes of pidtree_mon itself never match, including clients and their
+    /// ancestors mentioning pidtree_mon, like `sh -c 'pidtree_mon --cmdline-regex cargo'`.
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    pub cmdline_regex: Vec<String>,
+    /// Also monitor the processes running the executable at this path.
+    ///
+    /// See --comm.
+    #[arg(long, value_name = "PATH")]
+    pub exe: Vec<String>,
     /// The maximum time to collect statistics.
     #[arg(short, long, value_parser = parse_timeout_duration)]
     pub timeout: Option<Duration>,
+    /// Print a single line and exit, same as `--count 1`.
+    #[arg(long, conflicts_with = "count")]
+    pub once: bool,
+    /// Print this many lines, each averaged over a whole interval, and exit.
+    #[arg(short = 'n', long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub count: Option<u64>,
+    /// The interval in seconds between consecutive updates, may be fractional.
+    #[arg(short, long, value_name = "SECONDS", value_parser = parse_interval, default_value = "1")]
+    pub interval: Duration,
     #[arg(
         name = "field",
         short,
         long,
         help = concat!(
-            "sum[_t][:FMT] | all_loads[_t][:FMT] | TEST\n",
-            "FMT := .N | %N | TEST\n",
-            "TEST := if_range:[L]..[H]:then[:else] | if_greater:thr:then[:else]\n"
+            "SRC[_t][:FMT] | TEST\n",
+            "SRC := sum | all_loads | lifetime | all_lifetime | mem | all_mem | pss | all_pss\n",
+            "     | io_read | all_io_read | io_write | all_io_write\n",
+            "     | procs | all_procs | threads | all_threads | hidden | all_hidden\n",
+            "FMT := (.N | %N | bN | rN)[:PLACEHOLDER] | TEST\n",
+            "TEST := if_range:[L]..[H]:then[:else] | if_greater:thr:then[:else]\n",
+            "      | if_missing:then[:else]\n"
         ),
         default_values = ["sum", "all_loads"]
     )]
@@ -47,6 +113,191 @@ pub struct Config {
     /// The field separator.
     #[arg(short, long, default_value = " ")]
     pub separator: String,
+    /// The format of each printed line.
+    #[arg(short, long, value_enum, default_value_t = Output::Text)]
+    pub output: Output,
+    /// A field whose output is the color of the text in a status bar, e.g.
+    /// `sum_t:if_range:0.5..:#ff0000`.
+    ///
+    /// Used by the i3bar and polybar outputs; empty output means the default color.
+    #[arg(long, value_name = "field")]
+    pub color: Option<Field>,
+    /// A field whose output is a CSS class of the module, e.g. `sum:if_range:1..:busy`.
+    ///
+    /// Used by the waybar output; can be given multiple times and empty output means no class.
+    #[arg(long = "class", value_name = "field")]
+    pub classes: Vec<Field>,
+    /// The placeholder printed instead of unknown values, unless a field specifies its own.
+    #[arg(long, value_name = "PLACEHOLDER", default_value = "NaN")]
+    pub missing: String,
+    /// A file to append the statistics of each update to, for offline analysis.
+    ///
+    /// Each row contains the UNIX timestamp, the load of each process tree and their sum, followed
+    /// by the same for each of --log-metrics. Unknown values are left empty. The file starts with a
+    /// header row; an existing file with a different header is rotated first.
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// The format of the log file.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = LogFormat::Csv)]
+    pub log_format: LogFormat,
+    /// Additional statistics to record in the log file, comma-separated.
+    #[arg(long, value_enum, value_name = "METRIC", value_delimiter = ',')]
+    pub log_metrics: Vec<Metric>,
+    /// The size at which the log file is rotated, in bytes, optionally followed by K, M or G.
+    ///
+    /// The previous file is kept with `.1` appended to its name, replacing an older one.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub log_max_size: Option<u64>,
+    /// Exit with status 3 as soon as all monitored process trees are gone, after each of them has
+    /// been running.
+    #[arg(long)]
+    pub exit_when_gone: bool,
+    /// The time in seconds without any clients after which the daemon exits, 0 to never exit.
+    ///
+    /// Only applies to a daemon spawned by this invocation.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout_duration, default_value = "60")]
+    pub idle_timeout: Duration,
+    /// Measure in this process instead of using the daemon.
+    ///
+    /// Useful where a background process cannot or should not be spawned. Each invocation then
+    /// reads all of /proc on its own.
+    #[arg(long)]
+    pub no_daemon: bool,
+    /// Record the information about all processes read by the daemon to a file, for reproducing
+    /// the statistics with --replay.
+    ///
+    /// Only applies to a daemon spawned by this invocation, including with `daemon restart`, or
+    /// with --no-daemon. An existing file is replaced.
+    #[arg(long, value_name = "PATH", global = true)]
+    pub record: Option<PathBuf>,
+    /// Compute the statistics from a file written with --record instead of measuring.
+    ///
+    /// All updates are printed at once, as of the time they were recorded.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["record", "no_daemon"])]
+    pub replay: Option<PathBuf>,
+    /// Do not replace a running daemon of a different version than this client.
+    ///
+    /// An incompatible daemon is reported as an error.
+    #[arg(long)]
+    pub no_daemon_restart: bool,
+    /// The path of the daemon's socket.
+    ///
+    /// Defaults to `pidtree_mon.sock` in `$XDG_RUNTIME_DIR/pidtree_mon`, or in
+    /// `/tmp/pidtree_mon-<uid>` if `XDG_RUNTIME_DIR` is not set.
+    #[arg(long, value_name = "PATH", env = "PIDTREE_MON_SOCKET", global = true)]
+    pub socket: Option<PathBuf>,
+    /// The path of the daemon's PID file.
+    ///
+    /// Defaults to `pidtree_mon.pid` in the same directory as the socket.
+    #[arg(long, value_name = "PATH", env = "PIDTREE_MON_PID_FILE", global = true)]
+    pub pid_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// The number of lines to print before exiting, or `None` for no limit.
+    pub fn line_count(&self) -> Option<u64> {
+        if self.once {
+            Some(1)
+        } else {
+            self.count
+        }
+    }
+
+    /// Check if any of the requested statistics require the proportional set size to be measured.
+    pub fn needs_pss(&self) -> bool {
+        self.fields.iter().any(|f| f.0.needs_pss()) || self.log_metrics.contains(&Metric::Pss)
+    }
+
+    /// The requested selectors, in the order in which they are reported.
+    pub fn selectors(&self) -> Vec<Selector> {
+        let comm = self.comm.iter().cloned().map(Selector::Comm);
+        let cmdline = self
+            .cmdline_regex
+            .iter()
+            .cloned()
+            .map(Selector::CmdlineRegex);
+        let exe = self.exe.iter().cloned().map(Selector::Exe);
+        comm.chain(cmdline).chain(exe).collect()
+    }
+
+    /// The names of the monitored process trees: each PID, followed by each selector.
+    pub fn labels(&self) -> Vec<String> {
+        let pids = self.pids.iter().map(i32::to_string);
+        let selectors = self.selectors().into_iter().map(|selector| match selector {
+            Selector::Comm(comm) => format!("comm={comm}"),
+            Selector::CmdlineRegex(regex) => format!("cmdline={regex}"),
+            Selector::Exe(exe) => format!("exe={exe}"),
+        });
+        pids.chain(selectors).collect()
+    }
+}
+
+/// The format of output lines.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    /// The fields joined with the separator.
+    Text,
+    /// A JSON object with all statistics of each process tree and their sums, one per line;
+    /// fields are ignored.
+    Json,
+    /// The i3bar protocol (also used by swaybar), with a single block per update.
+    I3bar,
+    /// A JSON object for a waybar custom module with `return-type` set to `json`.
+    Waybar,
+    /// Text colored using polybar's format tags, for a script module with `tail = true`.
+    Polybar,
+}
+
+/// The format of the log file.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Comma-separated values.
+    Csv,
+    /// Tab-separated values.
+    Tsv,
+}
+
+/// A statistic of process trees which can be recorded in the log file in addition to the load.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[value(rename_all = "snake_case")]
+pub enum Metric {
+    /// Average load since the root process started.
+    Lifetime,
+    /// Resident set size in bytes.
+    Mem,
+    /// Proportional set size in bytes.
+    Pss,
+    /// Storage read rate in bytes per second.
+    IoRead,
+    /// Storage write rate in bytes per second.
+    IoWrite,
+    /// The number of processes.
+    Procs,
+    /// The number of threads.
+    Threads,
+    /// The number of processes hidden by hidepid.
+    Hidden,
+}
+
+/// A command to run instead of monitoring processes.
+#[derive(clap::Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Control the daemon.
+    Daemon {
+        #[command(subcommand)]
+        action: DaemonAction,
+    },
+}
+
+/// An action to perform on the daemon.
+#[derive(clap::Subcommand, Debug, PartialEq)]
+pub enum DaemonAction {
+    /// Print the status of the running daemon.
+    Status,
+    /// Stop the running daemon, disconnecting all its clients.
+    Stop,
+    /// Stop the running daemon, if any, and start a new one.
+    Restart,
 }
 
 fn parse_timeout_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
@@ -54,9 +305,38 @@ fn parse_timeout_duration(arg: &str) -> Result<std::time::Duration, std::num::Pa
     Ok(std::time::Duration::from_secs(seconds))
 }
 
+fn parse_interval(arg: &str) -> Result<Duration, String> {
+    let seconds: f32 = arg.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f32(seconds)
+        .ok()
+        .filter(|interval| !interval.is_zero())
+        .ok_or_else(|| "must be a positive number of seconds".to_owned())
+}
+
+fn parse_regex(arg: &str) -> Result<String, String> {
+    regex::Regex::new(arg).map_err(|e| format!("{e}"))?;
+    Ok(arg.to_owned())
+}
+
+fn parse_size(arg: &str) -> Result<u64, String> {
+    let (number, multiplier) = match arg.char_indices().last() {
+        Some((i, 'K')) => (&arg[..i], 1 << 10),
+        Some((i, 'M')) => (&arg[..i], 1 << 20),
+        Some((i, 'G')) => (&arg[..i], 1 << 30),
+        _ => (arg, 1),
+    };
+    let size: u64 = number.parse().map_err(|e| format!("{e}"))?;
+    size.checked_mul(multiplier)
+        .filter(|&size| size > 0)
+        .ok_or_else(|| "must be a positive size".to_owned())
+}
+
 /// Specification of one or more fields of information to print about a collection of PIDs.
+///
+/// The last element is the placeholder to print instead of unknown values, if it is different
+/// from the default one.
 #[derive(Clone, Debug, PartialEq)]
-pub struct Field(pub Source, pub Scale, pub Format);
+pub struct Field(pub Source, pub Scale, pub Format, pub Option<String>);
 
 /// Source of load values for a field specification.
 #[derive(Clone, Debug, PartialEq)]
@@ -65,14 +345,106 @@ pub enum Source {
     Sum,
     /// CPU usage of each process tree, one in each field
     AllLoads,
+    /// The sum of all process trees' average CPU usage since their roots started as a field
+    Lifetime,
+    /// Average CPU usage of each process tree since its root started, one in each field
+    AllLifetime,
+    /// The sum of all process trees' resident set sizes as a field
+    Mem,
+    /// Resident set size of each process tree, one in each field
+    AllMem,
+    /// The sum of all process trees' proportional set sizes as a field
+    Pss,
+    /// Proportional set size of each process tree, one in each field
+    AllPss,
+    /// The sum of all process trees' storage read rates as a field
+    IoRead,
+    /// Storage read rate of each process tree, one in each field
+    AllIoRead,
+    /// The sum of all process trees' storage write rates as a field
+    IoWrite,
+    /// Storage write rate of each process tree, one in each field
+    AllIoWrite,
+    /// The total number of processes in all process trees as a field
+    Procs,
+    /// The number of processes in each process tree, one in each field
+    AllProcs,
+    /// The total number of threads in all process trees as a field
+    Threads,
+    /// The number of threads in each process tree, one in each field
+    AllThreads,
+    /// The total number of hidden processes in all process trees as a field
+    Hidden,
+    /// The number of hidden processes in each process tree, one in each field
+    AllHidden,
+}
+
+impl Source {
+    /// Check if the source requires the proportional set size to be measured.
+    pub fn needs_pss(&self) -> bool {
+        matches!(self, Source::Pss | Source::AllPss)
+    }
+
+    /// Check if the source produces one field for each process tree instead of a sum.
+    pub fn is_per_tree(&self) -> bool {
+        matches!(
+            self,
+            Source::AllLoads
+                | Source::AllLifetime
+                | Source::AllMem
+                | Source::AllPss
+                | Source::AllIoRead
+                | Source::AllIoWrite
+                | Source::AllProcs
+                | Source::AllThreads
+                | Source::AllHidden
+        )
+    }
+
+    /// Check if the source has a total value that it can be scaled to ([Scale::OfTotal]).
+    fn has_total(&self) -> bool {
+        matches!(
+            self,
+            Source::Sum
+                | Source::AllLoads
+                | Source::Lifetime
+                | Source::AllLifetime
+                | Source::Mem
+                | Source::AllMem
+                | Source::Pss
+                | Source::AllPss
+        )
+    }
+
+    fn default_format(&self, scale: &Scale) -> Format {
+        match (self, scale) {
+            (Source::Mem | Source::AllMem | Source::Pss | Source::AllPss, Scale::OfCore) => {
+                Format::Bytes(1)
+            }
+            (Source::IoRead | Source::AllIoRead | Source::IoWrite | Source::AllIoWrite, _) => {
+                Format::ByteRate(1)
+            }
+            (
+                Source::Procs
+                | Source::AllProcs
+                | Source::Threads
+                | Source::AllThreads
+                | Source::Hidden
+                | Source::AllHidden,
+                _,
+            ) => Format::Float(0),
+            _ => Format::default(),
+        }
+    }
 }
 
 /// How to scale load values.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Scale {
-    /// As a fraction of a single core
+    /// As a fraction of a single core (or not scaled at all for memory sizes)
     OfCore,
     /// As a fraction of the total computing power ([Scale::OfCore], but divided by number of cores)
+    /// or of the total memory
     OfTotal,
 }
 
@@ -83,6 +455,10 @@ pub enum Format {
     Float(u8),
     /// Print load as a percent of its base value ([Scale]) with a certain precision.
     Percent(u8),
+    /// Print a size in bytes using binary units with a certain precision.
+    Bytes(u8),
+    /// Print a rate in bytes per second using binary units with a certain precision.
+    ByteRate(u8),
     /// Print one string when load is above a threshold, or a different string otherwise.
     IfThenElse {
         /// The test.
@@ -108,14 +484,17 @@ pub enum Test {
     /// Evaluates to true iff value is in given range, left-inclusive, right-exclusive. If either
     /// boundar is `None`, this boundary is not tested.
     Range(Option<f32>, Option<f32>),
+    /// Test if value is unknown.
+    Missing,
 }
 
 impl Test {
     pub fn matches(&self, value: f32) -> bool {
         match &self {
             Test::Range(lo, hi) => {
-                lo.map_or(true, |lo| lo <= value) && hi.map_or(true, |hi| value < hi)
+                lo.is_none_or(|lo| lo <= value) && hi.is_none_or(|hi| value < hi)
             }
+            Test::Missing => value.is_nan(),
         }
     }
 }
@@ -129,22 +508,7 @@ impl FromStr for Field {
             .expect("splitn should produce at least 1 elment");
         match field {
             "" => Err("missing field name")?,
-            "sum" | "all_loads" | "sum_t" | "all_loads_t" => {
-                let (source, scale) = match field {
-                    "sum" => (Source::Sum, Scale::OfCore),
-                    "sum_t" => (Source::Sum, Scale::OfTotal),
-                    "all_loads" => (Source::AllLoads, Scale::OfCore),
-                    "all_loads_t" => (Source::AllLoads, Scale::OfTotal),
-                    _ => panic!(),
-                };
-                let format = tokens
-                    .next()
-                    .map(parse_format)
-                    .transpose()?
-                    .unwrap_or_default();
-                Ok(Field(source, scale, format))
-            }
-            "if_range" | "if_greater" => {
+            "if_range" | "if_greater" | "if_missing" => {
                 let args = tokens
                     .next()
                     .ok_or(format!("missing arguments to {field}"))?;
@@ -152,24 +516,67 @@ impl FromStr for Field {
                     Source::Sum,
                     Scale::OfCore,
                     parse_test_format(field, args)?,
+                    None,
                 ))
             }
-            _ => Err(format!("unrecognized field {field}"))?,
+            source => {
+                let (source, scale) =
+                    parse_source(source).ok_or(format!("unrecognized field {field}"))?;
+                let (format, placeholder) = tokens
+                    .next()
+                    .map(parse_format)
+                    .transpose()?
+                    .unwrap_or_else(|| (source.default_format(&scale), None));
+                Ok(Field(source, scale, format, placeholder))
+            }
         }
     }
 }
 
-fn parse_format(s: &str) -> Result<Format, String> {
+fn parse_source(s: &str) -> Option<(Source, Scale)> {
+    let (name, scale) = match s.strip_suffix("_t") {
+        Some(name) => (name, Scale::OfTotal),
+        None => (s, Scale::OfCore),
+    };
+    let source = match name {
+        "sum" => Source::Sum,
+        "all_loads" => Source::AllLoads,
+        "lifetime" => Source::Lifetime,
+        "all_lifetime" => Source::AllLifetime,
+        "mem" => Source::Mem,
+        "all_mem" => Source::AllMem,
+        "pss" => Source::Pss,
+        "all_pss" => Source::AllPss,
+        "io_read" => Source::IoRead,
+        "all_io_read" => Source::AllIoRead,
+        "io_write" => Source::IoWrite,
+        "all_io_write" => Source::AllIoWrite,
+        "procs" => Source::Procs,
+        "all_procs" => Source::AllProcs,
+        "threads" => Source::Threads,
+        "all_threads" => Source::AllThreads,
+        "hidden" => Source::Hidden,
+        "all_hidden" => Source::AllHidden,
+        _ => None?,
+    };
+    if scale == Scale::OfTotal && !source.has_total() {
+        None?
+    }
+    Some((source, scale))
+}
+
+/// Parse a format specifier and the optional placeholder following it.
+fn parse_format(s: &str) -> Result<(Format, Option<String>), String> {
     let mut tokens = s.splitn(2, ':');
     let field = tokens
         .next()
         .expect("splitn should produce at least 1 elment");
     match field {
-        "if_range" | "if_greater" => {
+        "if_range" | "if_greater" | "if_missing" => {
             let args = tokens
                 .next()
                 .ok_or(format!("missing arguments to {field}"))?;
-            parse_test_format(field, args)
+            Ok((parse_test_format(field, args)?, None))
         }
         numeric => {
             let prefix = numeric
@@ -180,16 +587,31 @@ fn parse_format(s: &str) -> Result<Format, String> {
                 .expect("rest should exist")
                 .parse()
                 .map_err(|e| format!("cannot parse precision: {e}"));
-            match prefix {
-                "." => Ok(Format::Float(digits?)),
-                "%" => Ok(Format::Percent(digits?)),
-                _ => Err(format!("unrecognized format specifier `{numeric}`")),
-            }
+            let format = match prefix {
+                "." => Format::Float(digits?),
+                "%" => Format::Percent(digits?),
+                "b" => Format::Bytes(digits?),
+                "r" => Format::ByteRate(digits?),
+                _ => Err(format!("unrecognized format specifier `{numeric}`"))?,
+            };
+            Ok((format, tokens.next().map(str::to_owned)))
         }
     }
 }
 
 fn parse_test_format(format: &str, args: &str) -> Result<Format, String> {
+    if format == "if_missing" {
+        let mut tokens = args.splitn(2, ':');
+        let then = tokens
+            .next()
+            .expect("splitn should produce at least 1 elment");
+        let otherwise = tokens.next().unwrap_or_default();
+        return Ok(Format::IfThenElse {
+            test: Test::Missing,
+            then: then.to_owned(),
+            otherwise: otherwise.to_owned(),
+        });
+    }
     let mut tokens = args.splitn(3, ':');
     let test = tokens
         .next()
@@ -239,6 +661,117 @@ impl FromStr for Test {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parses_daemon_commands() {
+        use clap::Parser as _;
+
+        let config = Config::try_parse_from(["pidtree_mon", "daemon", "status"]).unwrap();
+        assert_eq!(
+            config.command,
+            Some(Command::Daemon {
+                action: DaemonAction::Status
+            })
+        );
+        let config =
+            Config::try_parse_from(["pidtree_mon", "daemon", "stop", "--socket", "/x"]).unwrap();
+        assert_eq!(
+            config.command,
+            Some(Command::Daemon {
+                action: DaemonAction::Stop
+            })
+        );
+        assert_eq!(config.socket, Some("/x".into()));
+        let config = Config::try_parse_from(["pidtree_mon", "1", "2"]).unwrap();
+        assert_eq!(config.command, None);
+        assert_eq!(config.pids, vec![1, 2]);
+        assert!(Config::try_parse_from(["pidtree_mon"]).is_err());
+        assert!(Config::try_parse_from(["pidtree_mon", "daemon"]).is_err());
+    }
+
+    #[test]
+    fn parses_line_count() {
+        use clap::Parser as _;
+
+        let config = Config::try_parse_from(["pidtree_mon", "1"]).unwrap();
+        assert_eq!(config.line_count(), None);
+        let config = Config::try_parse_from(["pidtree_mon", "--once", "1"]).unwrap();
+        assert_eq!(config.line_count(), Some(1));
+        let config = Config::try_parse_from(["pidtree_mon", "-n", "3", "1"]).unwrap();
+        assert_eq!(config.line_count(), Some(3));
+        assert!(Config::try_parse_from(["pidtree_mon", "-n", "0", "1"]).is_err());
+        assert!(Config::try_parse_from(["pidtree_mon", "--once", "-n", "2", "1"]).is_err());
+    }
+
+    #[test]
+    fn parses_log_options() {
+        use clap::Parser as _;
+
+        let config = Config::try_parse_from([
+            "pidtree_mon",
+            "--log-file",
+            "/x.csv",
+            "--log-metrics",
+            "mem,io_read",
+            "--log-metrics",
+            "pss",
+            "1",
+        ])
+        .unwrap();
+        assert_eq!(config.log_file, Some("/x.csv".into()));
+        assert_eq!(config.log_format, LogFormat::Csv);
+        assert_eq!(
+            config.log_metrics,
+            vec![Metric::Mem, Metric::IoRead, Metric::Pss]
+        );
+        assert!(config.needs_pss());
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size("1M"), Ok(1 << 20));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("1T").is_err());
+    }
+
+    #[test]
+    fn parses_selectors() {
+        use clap::Parser as _;
+
+        let config = Config::try_parse_from([
+            "pidtree_mon",
+            "--exe",
+            "/usr/bin/rustc",
+            "--cmdline-regex",
+            "cargo (build|test)",
+            "--comm",
+            "firefox",
+            "--comm",
+            "chrome",
+        ])
+        .unwrap();
+        assert!(config.pids.is_empty());
+        assert_eq!(
+            config.selectors(),
+            vec![
+                Selector::Comm("firefox".to_owned()),
+                Selector::Comm("chrome".to_owned()),
+                Selector::CmdlineRegex("cargo (build|test)".to_owned()),
+                Selector::Exe("/usr/bin/rustc".to_owned()),
+            ]
+        );
+        let config = Config::try_parse_from(["pidtree_mon", "--comm", "sh", "1"]).unwrap();
+        assert_eq!(config.labels(), vec!["1", "comm=sh"]);
+        assert!(Config::try_parse_from(["pidtree_mon", "--cmdline-regex", "("]).is_err());
+    }
+
+    #[test]
+    fn parses_interval() {
+        assert_eq!(parse_interval("0.25"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_interval("5"), Ok(Duration::from_secs(5)));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("-1").is_err());
+        assert!(parse_interval("x").is_err());
+    }
+
     #[test]
     fn test_matches_full_range() {
         let t = Test::Range(Some(1.0), Some(2.0));
@@ -307,10 +840,13 @@ mod tests {
     #[test]
     fn parses_simple() {
         for (spec, field) in [
-            ("sum", Field(Source::Sum, Scale::OfCore, Format::Float(2))),
+            (
+                "sum",
+                Field(Source::Sum, Scale::OfCore, Format::Float(2), None),
+            ),
             (
                 "all_loads",
-                Field(Source::AllLoads, Scale::OfCore, Format::Float(2)),
+                Field(Source::AllLoads, Scale::OfCore, Format::Float(2), None),
             ),
         ] {
             let f: Field = spec.parse().unwrap();
@@ -320,6 +856,176 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parses_mem() {
+        for (spec, field) in [
+            (
+                "mem",
+                Field(Source::Mem, Scale::OfCore, Format::Bytes(1), None),
+            ),
+            (
+                "all_pss",
+                Field(Source::AllPss, Scale::OfCore, Format::Bytes(1), None),
+            ),
+            (
+                "mem_t",
+                Field(Source::Mem, Scale::OfTotal, Format::Float(2), None),
+            ),
+            (
+                "all_mem:b0",
+                Field(Source::AllMem, Scale::OfCore, Format::Bytes(0), None),
+            ),
+            (
+                "pss_t:%1",
+                Field(Source::Pss, Scale::OfTotal, Format::Percent(1), None),
+            ),
+        ] {
+            let f: Field = spec.parse().unwrap();
+            assert_eq!(f, field);
+        }
+        let f: Result<Field, _> = "mem_x".parse();
+        assert!(f.is_err());
+        let f: Result<Field, _> = "mem:b".parse();
+        assert!(f.is_err());
+    }
+
+    #[test]
+    fn parses_io() {
+        for (spec, field) in [
+            (
+                "io_read",
+                Field(Source::IoRead, Scale::OfCore, Format::ByteRate(1), None),
+            ),
+            (
+                "all_io_write",
+                Field(Source::AllIoWrite, Scale::OfCore, Format::ByteRate(1), None),
+            ),
+            (
+                "io_write:r2",
+                Field(Source::IoWrite, Scale::OfCore, Format::ByteRate(2), None),
+            ),
+            (
+                "io_read:.0",
+                Field(Source::IoRead, Scale::OfCore, Format::Float(0), None),
+            ),
+        ] {
+            let f: Field = spec.parse().unwrap();
+            assert_eq!(f, field);
+        }
+        let f: Result<Field, _> = "io_read_t".parse();
+        assert!(f.is_err());
+    }
+
+    #[test]
+    fn parses_counts() {
+        for (spec, field) in [
+            (
+                "procs",
+                Field(Source::Procs, Scale::OfCore, Format::Float(0), None),
+            ),
+            (
+                "all_threads",
+                Field(Source::AllThreads, Scale::OfCore, Format::Float(0), None),
+            ),
+        ] {
+            let f: Field = spec.parse().unwrap();
+            assert_eq!(f, field);
+        }
+        let f: Field = "all_procs:if_range:10..:many:few".parse().unwrap();
+        assert_eq!(
+            f,
+            Field(
+                Source::AllProcs,
+                Scale::OfCore,
+                Format::IfThenElse {
+                    test: Test::Range(Some(10.0), None),
+                    then: "many".to_owned(),
+                    otherwise: "few".to_owned(),
+                },
+                None
+            )
+        );
+        let f: Result<Field, _> = "threads_t".parse();
+        assert!(f.is_err());
+    }
+
+    #[test]
+    fn parses_missing() {
+        for (spec, field) in [
+            (
+                "all_loads:.1:-",
+                Field(
+                    Source::AllLoads,
+                    Scale::OfCore,
+                    Format::Float(1),
+                    Some("-".to_owned()),
+                ),
+            ),
+            (
+                "mem:b0:",
+                Field(
+                    Source::Mem,
+                    Scale::OfCore,
+                    Format::Bytes(0),
+                    Some("".to_owned()),
+                ),
+            ),
+            (
+                "all_loads:if_missing:gone:ok",
+                Field(
+                    Source::AllLoads,
+                    Scale::OfCore,
+                    Format::IfThenElse {
+                        test: Test::Missing,
+                        then: "gone".to_owned(),
+                        otherwise: "ok".to_owned(),
+                    },
+                    None,
+                ),
+            ),
+            (
+                "if_missing:?",
+                Field(
+                    Source::Sum,
+                    Scale::OfCore,
+                    Format::IfThenElse {
+                        test: Test::Missing,
+                        then: "?".to_owned(),
+                        otherwise: "".to_owned(),
+                    },
+                    None,
+                ),
+            ),
+        ] {
+            let f: Field = spec.parse().unwrap();
+            assert_eq!(f, field);
+        }
+        assert!(Test::Missing.matches(f32::NAN));
+        assert!(!Test::Missing.matches(0.0));
+    }
+
+    #[test]
+    fn parses_lifetime() {
+        for (spec, field) in [
+            (
+                "lifetime",
+                Field(Source::Lifetime, Scale::OfCore, Format::Float(2), None),
+            ),
+            (
+                "all_lifetime_t:%0",
+                Field(
+                    Source::AllLifetime,
+                    Scale::OfTotal,
+                    Format::Percent(0),
+                    None,
+                ),
+            ),
+        ] {
+            let f: Field = spec.parse().unwrap();
+            assert_eq!(f, field);
+        }
+    }
+
     #[test]
     fn parses_if_greater() {
         let f: Field = "if_greater:3:then".parse().unwrap();
@@ -331,6 +1037,7 @@ mod tests {
                 then,
                 otherwise,
             },
+            None,
         ) = f
         else {
             panic!("should parse");
@@ -348,6 +1055,7 @@ mod tests {
                 then,
                 otherwise,
             },
+            None,
         ) = f
         else {
             panic!("should parse");
@@ -365,6 +1073,7 @@ mod tests {
                 then,
                 otherwise,
             },
+            None,
         ) = f
         else {
             panic!("should parse");
@@ -382,6 +1091,7 @@ mod tests {
                 then,
                 otherwise,
             },
+            None,
         ) = f
         else {
             panic!("should parse");
@@ -399,6 +1109,7 @@ mod tests {
                 then,
                 otherwise,
             },
+            None,
         ) = f
         else {
             panic!("should parse");
@@ -413,19 +1124,19 @@ mod tests {
         let f: Field = "all_loads".parse().unwrap();
         assert!(matches!(
             f,
-            Field(Source::AllLoads, Scale::OfCore, Format::Float(2))
+            Field(Source::AllLoads, Scale::OfCore, Format::Float(2), None)
         ));
 
         let f: Field = "all_loads:.3".parse().unwrap();
         assert!(matches!(
             f,
-            Field(Source::AllLoads, Scale::OfCore, Format::Float(3))
+            Field(Source::AllLoads, Scale::OfCore, Format::Float(3), None)
         ));
 
         let f: Field = "all_loads:%0".parse().unwrap();
         assert!(matches!(
             f,
-            Field(Source::AllLoads, Scale::OfCore, Format::Percent(0))
+            Field(Source::AllLoads, Scale::OfCore, Format::Percent(0), None)
         ));
 
         let f: Result<Field, _> = "all_loads:%0d".parse();
@@ -440,6 +1151,7 @@ mod tests {
                 then,
                 otherwise,
             },
+            None,
         ) = f
         else {
             panic!("should parse");
@@ -0,0 +1,141 @@
+use std::{
+    io::ErrorKind,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use tokio::{io::BufReader, net::UnixStream as TokioUnixStream};
+
+use crate::{
+    client::handshake,
+    protocol::{Request, Response},
+};
+
+/// The maximum time to wait for the daemon to terminate.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(2);
+const TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Print the status of the daemon listening on `socket`.
+#[tokio::main(flavor = "current_thread")]
+pub async fn status(socket: &Path) -> Result<(), String> {
+    let stream = connect(socket).await?.ok_or("daemon is not running")?;
+    print_status(stream).await
+}
+
+/// Query the daemon connected via `stream` about its status and print it.
+pub async fn print_status(mut stream: TokioUnixStream) -> Result<(), String> {
+    let pid = stream.peer_cred().ok().and_then(|cred| cred.pid());
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let hello = handshake(&mut reader, &mut writer, &[]).await?;
+    Request::Status
+        .write_to(&mut writer)
+        .await
+        .map_err(|e| format!("error writing to daemon: {e}"))?;
+    let status = match Response::read_from(&mut reader).await {
+        Ok(Response::Status(status)) => status,
+        Ok(response) => Err(format!("unexpected response from daemon: {response:?}"))?,
+        Err(e) => Err(format!("error reading from daemon: {e}"))?,
+    };
+    if let Some(pid) = pid {
+        println!("pid:                  {pid}");
+    }
+    println!("version:              {}", hello.package_version);
+    println!("uptime:               {:.0?}", status.uptime);
+    println!("clients:              {}", status.clients);
+    println!("interval:             {:?}", status.interval);
+    println!("last sample duration: {:.1?}", status.last_sample_duration);
+    Ok(())
+}
+
+/// Stop the daemon listening on `socket` and wait until it exits.
+///
+/// The daemon is asked to shut down in an orderly fashion, disconnecting all its clients. If that
+/// is not possible or takes too long, it is terminated with a signal.
+pub fn stop(socket: &Path) -> Result<(), String> {
+    let Some(pid) = request_shutdown(socket)? else {
+        println!("daemon is not running");
+        return Ok(());
+    };
+    if wait_for_exit(pid).is_err() {
+        terminate_daemon(pid)?;
+    }
+    Ok(())
+}
+
+/// Ask the daemon to shut down, or send it a signal if it does not understand the request.
+///
+/// Returns the PID of the daemon, or `None` if it is not running.
+#[tokio::main(flavor = "current_thread")]
+async fn request_shutdown(socket: &Path) -> Result<Option<i32>, String> {
+    let Some(mut stream) = connect(socket).await? else {
+        return Ok(None);
+    };
+    let pid = stream
+        .peer_cred()
+        .ok()
+        .and_then(|cred| cred.pid())
+        .ok_or("cannot determine daemon PID")?;
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    if handshake(&mut reader, &mut writer, &[]).await.is_err() {
+        send_sigterm(pid)?;
+        return Ok(Some(pid));
+    }
+    Request::Shutdown
+        .write_to(&mut writer)
+        .await
+        .map_err(|e| format!("error writing to daemon: {e}"))?;
+    match Response::read_from(&mut reader).await {
+        Ok(Response::ShuttingDown) => Ok(Some(pid)),
+        Ok(response) => Err(format!("unexpected response from daemon: {response:?}")),
+        Err(e) => Err(format!("error reading from daemon: {e}")),
+    }
+}
+
+/// Connect to the daemon, or return `None` if it is not running.
+async fn connect(socket: &Path) -> Result<Option<TokioUnixStream>, String> {
+    match TokioUnixStream::connect(socket).await {
+        Ok(stream) => Ok(Some(stream)),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            Ok(None)
+        }
+        Err(e) => Err(format!("could not connect to daemon: {e}")),
+    }
+}
+
+/// Terminate the daemon process and wait until it exits.
+pub fn terminate_daemon(pid: i32) -> Result<(), String> {
+    send_sigterm(pid)?;
+    wait_for_exit(pid)
+}
+
+fn send_sigterm(pid: i32) -> Result<(), String> {
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ESRCH) {
+            // someone else was faster
+            return Ok(());
+        }
+        Err(format!("cannot terminate daemon: {e}"))?
+    }
+    Ok(())
+}
+
+fn wait_for_exit(pid: i32) -> Result<(), String> {
+    let deadline = Instant::now() + TERMINATE_TIMEOUT;
+    while is_running(pid) {
+        if Instant::now() > deadline {
+            Err(format!("daemon (PID {pid}) did not terminate"))?
+        }
+        std::thread::sleep(TERMINATE_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Check if a process exists and is not a zombie.
+fn is_running(pid: i32) -> bool {
+    procfs::process::Process::new(pid)
+        .and_then(|prc| prc.stat())
+        .is_ok_and(|stat| stat.state != 'Z')
+}
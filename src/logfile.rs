@@ -0,0 +1,253 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufRead as _, BufReader, ErrorKind, Write as _},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    client::sum,
+    config::{Config, LogFormat, Metric},
+    protocol::{TreeState, TreeStats},
+};
+
+/// A CSV or TSV file the statistics of each update are appended to, see [Config::log_file].
+pub struct LogFile {
+    path: PathBuf,
+    separator: char,
+    metrics: Vec<Metric>,
+    max_size: Option<u64>,
+    header: String,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    /// Open the log file requested by `config`, if any, for the process trees named `labels`, see
+    /// [Config::labels].
+    ///
+    /// A header row is written to a new file. An existing file with a different header, for
+    /// example with other PIDs, is rotated first.
+    pub fn open(config: &Config, labels: &[String]) -> Result<Option<Self>, String> {
+        let Some(path) = &config.log_file else {
+            return Ok(None);
+        };
+        let separator = match config.log_format {
+            LogFormat::Csv => ',',
+            LogFormat::Tsv => '\t',
+        };
+        let mut header = "timestamp".to_owned();
+        for name in ["load"]
+            .into_iter()
+            .chain(config.log_metrics.iter().copied().map(metric_name))
+        {
+            for label in labels {
+                let column = quote(&format!("{name}_{label}"), separator);
+                write!(header, "{separator}{column}").unwrap();
+            }
+            write!(header, "{separator}{name}_sum").unwrap();
+        }
+        header.push('\n');
+        let (file, size) = open_appending(path)?;
+        let mut log = LogFile {
+            path: path.clone(),
+            separator,
+            metrics: config.log_metrics.clone(),
+            max_size: config.log_max_size,
+            header,
+            file,
+            size,
+        };
+        if log.size == 0 {
+            log.write_header()?;
+        } else if read_first_line(path)? != log.header {
+            log.rotate()?;
+        }
+        Ok(Some(log))
+    }
+
+    /// Append a row with the statistics of an update, rotating the file if it gets too large.
+    pub fn write(&mut self, timestamp: f64, trees: &[TreeState]) -> Result<(), String> {
+        let stats: Vec<_> = trees.iter().map(TreeState::stats).collect();
+        let mut row = format!("{timestamp:.3}");
+        let metrics = [(|s: &TreeStats| s.load) as fn(&TreeStats) -> f32]
+            .into_iter()
+            .chain(self.metrics.iter().copied().map(metric_value));
+        for metric in metrics {
+            let values: Vec<_> = stats.iter().map(metric).collect();
+            for value in values.iter().copied().chain([sum(&values)]) {
+                row.push(self.separator);
+                if !value.is_nan() {
+                    write!(row, "{value}").unwrap();
+                }
+            }
+        }
+        row.push('\n');
+        let header_size = self.header.len() as u64;
+        if self
+            .max_size
+            .is_some_and(|max| self.size > header_size && self.size + row.len() as u64 > max)
+        {
+            self.rotate()?;
+        }
+        self.append(&row)
+    }
+
+    /// Move the current file aside, replacing any previously rotated one, and start a new one.
+    fn rotate(&mut self) -> Result<(), String> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, &rotated)
+            .map_err(|e| format!("cannot rotate log file {}: {e}", self.path.display()))?;
+        (self.file, self.size) = open_appending(&self.path)?;
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> Result<(), String> {
+        let header = self.header.clone();
+        self.append(&header)
+    }
+
+    fn append(&mut self, data: &str) -> Result<(), String> {
+        self.file
+            .write_all(data.as_bytes())
+            .map_err(|e| format!("cannot write log file {}: {e}", self.path.display()))?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Quote a header column which would otherwise be split, as in CSV.
+fn quote(column: &str, separator: char) -> String {
+    if column.contains([separator, '"', '\n']) {
+        format!("\"{}\"", column.replace('"', "\"\""))
+    } else {
+        column.to_owned()
+    }
+}
+
+fn metric_name(metric: Metric) -> &'static str {
+    match metric {
+        Metric::Lifetime => "lifetime",
+        Metric::Mem => "mem",
+        Metric::Pss => "pss",
+        Metric::IoRead => "io_read",
+        Metric::IoWrite => "io_write",
+        Metric::Procs => "procs",
+        Metric::Threads => "threads",
+        Metric::Hidden => "hidden",
+    }
+}
+
+fn metric_value(metric: Metric) -> fn(&TreeStats) -> f32 {
+    match metric {
+        Metric::Lifetime => |s| s.lifetime,
+        Metric::Mem => |s| s.rss,
+        Metric::Pss => |s| s.pss,
+        Metric::IoRead => |s| s.io_read,
+        Metric::IoWrite => |s| s.io_write,
+        Metric::Procs => |s| s.procs,
+        Metric::Threads => |s| s.threads,
+        Metric::Hidden => |s| s.hidden,
+    }
+}
+
+/// Open a file for appending, creating it if needed, and determine its current size.
+fn open_appending(path: &Path) -> Result<(File, u64), String> {
+    let error = |e| format!("cannot open log file {}: {e}", path.display());
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(error)?;
+    let size = file.metadata().map_err(error)?.len();
+    Ok((file, size))
+}
+
+fn read_first_line(path: &Path) -> Result<String, String> {
+    let mut line = String::new();
+    match File::open(path).and_then(|f| BufReader::new(f).read_line(&mut line)) {
+        Ok(_) => Ok(line),
+        Err(e) if e.kind() == ErrorKind::InvalidData => Ok(line),
+        Err(e) => Err(format!("cannot read log file {}: {e}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    #[test]
+    fn writes_and_rotates() {
+        let path =
+            std::env::temp_dir().join(format!("pidtree_mon-test-{}.tsv", std::process::id()));
+        let rotated = path.with_extension("tsv.1");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+        let config = Config::try_parse_from([
+            "pidtree_mon",
+            "--log-file",
+            path.to_str().unwrap(),
+            "--log-format=tsv",
+            "--log-metrics=procs",
+            "--log-max-size=120",
+            "1",
+            "2",
+        ])
+        .unwrap();
+        let trees = [
+            TreeState::Alive(TreeStats {
+                load: 1.5,
+                procs: 3.0,
+                ..TreeStats::MISSING
+            }),
+            TreeState::Gone,
+        ];
+        let header = "timestamp\tload_1\tload_2\tload_sum\tprocs_1\tprocs_2\tprocs_sum\n";
+        let row = "1700000000.500\t1.5\t\t1.5\t3\t\t3\n";
+        let mut log = LogFile::open(&config, &config.labels()).unwrap().unwrap();
+        log.write(1_700_000_000.5, &trees).unwrap();
+        log.write(1_700_000_000.5, &trees).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, format!("{header}{row}{row}"));
+        // the next row would exceed the maximum size
+        log.write(1_700_000_000.5, &trees).unwrap();
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap(), contents);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{header}{row}")
+        );
+        drop(log);
+        // reopening with the same columns appends
+        let mut log = LogFile::open(&config, &config.labels()).unwrap().unwrap();
+        log.write(1_700_000_000.5, &trees).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{header}{row}{row}")
+        );
+        // while other columns start a new file
+        LogFile::open(&config, &["1".to_owned()]).unwrap().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap(),
+            format!("{header}{row}{row}")
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "timestamp\tload_1\tload_sum\tprocs_1\tprocs_sum\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    fn quotes_columns() {
+        assert_eq!(quote("load_comm=sh", ','), "load_comm=sh");
+        assert_eq!(
+            quote(r#"load_cmdline=a{1,2}"b""#, ','),
+            r#""load_cmdline=a{1,2}""b""""#
+        );
+        assert_eq!(quote("load_cmdline=a{1,2}", '\t'), "load_cmdline=a{1,2}");
+    }
+}
@@ -1,23 +1,33 @@
-use std::{process::ExitCode, time::Duration};
+use std::{future::Future, path::Path, process::ExitCode, sync::Arc};
 
 use clap::Parser as _;
-use log::error;
+use log::{error, info};
+use tokio::net::UnixStream as TokioUnixStream;
 use with_daemon::with_daemon;
 
-use config::Config;
-use worker::Worker;
+use client::{Capacity, ClientError, Outcome, Printer};
+use config::{Command, Config, DaemonAction};
+use paths::Paths;
+use protocol::Root;
+use snapshot::Recording;
+use worker::{Settings, Worker};
 
 mod client;
 mod config;
+mod control;
+mod logfile;
+mod paths;
+mod protocol;
+mod selector;
+mod snapshot;
 mod worker;
 
-const UPDATE_INTERVAL: Duration = Duration::from_millis(1000);
-const SOCKET_FILENAME: &str = "/tmp/pidtree_mon.sock";
-const PID_FILENAME: &str = "/tmp/pidtree_mon.pid";
+/// The exit status when all monitored process trees are gone, see [Config::exit_when_gone].
+const EXIT_ALL_GONE: u8 = 3;
 
 fn main() -> ExitCode {
     match entrypoint() {
-        Ok(()) => ExitCode::SUCCESS,
+        Ok(code) => code,
         Err(e) => {
             error!("{e}");
             println!("{e}");
@@ -26,24 +36,142 @@ fn main() -> ExitCode {
     }
 }
 
-fn entrypoint() -> Result<(), String> {
+fn entrypoint() -> Result<ExitCode, String> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("off")).init();
     let config = Config::parse();
-    let framework_res = with_daemon(
-        PID_FILENAME,
-        SOCKET_FILENAME,
-        |ctrl| Worker::new(UPDATE_INTERVAL, ctrl),
-        Worker::handle_client,
-        |stream| {
-            client::run(
-                stream,
-                config.pids,
-                config.timeout,
-                config.fields,
-                config.separator,
-            )
+    let paths = || Paths::resolve(config.socket.clone(), config.pid_file.clone());
+    let outcome = match &config.command {
+        Some(Command::Daemon { action }) => {
+            run_daemon_action(action, &config, &paths()?)?;
+            Outcome::Finished
+        }
+        None => match &config.replay {
+            Some(recording) => run_replay(&config, recording)?,
+            None if config.no_daemon => run_standalone(&config)?,
+            None => run_client(&config, &paths()?)?,
         },
-    );
-    let client_res = framework_res.map_err(|e| format!("framework: {e}"))?;
-    client_res.map_err(|e| format!("client: {e}"))
+    };
+    match outcome {
+        Outcome::Finished => Ok(ExitCode::SUCCESS),
+        Outcome::AllGone => Ok(ExitCode::from(EXIT_ALL_GONE)),
+    }
+}
+
+fn run_client(config: &Config, paths: &Paths) -> Result<Outcome, String> {
+    let mut replaced = false;
+    loop {
+        let client_res = with_worker(config, paths, |stream| {
+            client::run(stream, config, !config.no_daemon_restart && !replaced)
+        })?;
+        match client_res {
+            Err(ClientError::StaleDaemon {
+                pid: Some(pid),
+                reason,
+            }) if !config.no_daemon_restart && !replaced => {
+                info!("replacing daemon: {reason}");
+                control::terminate_daemon(pid).map_err(|e| format!("client: {e}"))?;
+                replaced = true;
+            }
+            res => return res.map_err(|e| format!("client: {e}")),
+        }
+    }
+}
+
+fn run_daemon_action(action: &DaemonAction, config: &Config, paths: &Paths) -> Result<(), String> {
+    match action {
+        DaemonAction::Status => control::status(&paths.socket),
+        DaemonAction::Stop => control::stop(&paths.socket),
+        DaemonAction::Restart => {
+            control::stop(&paths.socket)?;
+            with_worker(config, paths, control::print_status)?
+        }
+    }
+}
+
+/// Run a client of a [Worker] running in this process instead of in the daemon.
+#[tokio::main]
+async fn run_standalone(config: &Config) -> Result<Outcome, String> {
+    let settings = Settings {
+        idle_timeout: None,
+        ..worker_settings(config)?
+    };
+    let worker = Worker::new(settings, None).await?;
+    let (stream, worker_stream) =
+        TokioUnixStream::pair().map_err(|e| format!("cannot create socket pair: {e}"))?;
+    tokio::spawn(Arc::new(worker).handle_client(worker_stream));
+    client::run(stream, config, false)
+        .await
+        .map_err(|e| format!("client: {e}"))
+}
+
+/// Print the statistics computed from a recording instead of measuring.
+fn run_replay(config: &Config, recording: &Path) -> Result<Outcome, String> {
+    let recording = Recording::read(recording)?;
+    let roots: Vec<_> = config
+        .pids
+        .iter()
+        .map(|&pid| Root {
+            pid,
+            start_time: None,
+        })
+        .collect();
+    let capacity = Capacity {
+        cores: recording.system.cores,
+        memory: recording.system.memory,
+    };
+    let mut printer = Printer::new(config, capacity)?;
+    let wait_full = config.line_count().is_some();
+    let updates = worker::replay(
+        &recording,
+        &roots,
+        &config.selectors(),
+        config.interval,
+        wait_full,
+    )?;
+    for (timestamp, trees) in updates {
+        if let Some(outcome) = printer.print(timestamp, &trees)? {
+            return Ok(outcome);
+        }
+    }
+    Ok(Outcome::Finished)
+}
+
+/// Determine the settings of a new [Worker] from `config`.
+///
+/// A relative recording path is resolved against the current directory, as the daemon changes its
+/// directory to `/`.
+fn worker_settings(config: &Config) -> Result<Settings, String> {
+    Ok(Settings {
+        update_interval: config.interval,
+        measure_pss: config.needs_pss(),
+        idle_timeout: Some(config.idle_timeout).filter(|t| !t.is_zero()),
+        record: config.record.clone().map(paths::absolute).transpose()?,
+    })
+}
+
+/// Run a client of the daemon, spawning the daemon with a new [Worker] if it is not running.
+///
+/// The settings of the new worker are determined by `config`.
+fn with_worker<R, C, CFut>(config: &Config, paths: &Paths, client: C) -> Result<R, String>
+where
+    C: FnOnce(TokioUnixStream) -> CFut,
+    CFut: Future<Output = R>,
+{
+    let socket = paths.socket.to_str().ok_or("socket path is not UTF-8")?;
+    let pid_file = paths
+        .pid_file
+        .to_str()
+        .ok_or("PID file path is not UTF-8")?;
+    let settings = worker_settings(config)?;
+    with_daemon(
+        pid_file,
+        socket,
+        move |ctrl| Worker::new(settings, Some(ctrl)),
+        Worker::handle_client,
+        client,
+    )
+    .map_err(|e| match e {
+        with_daemon::Error::StateFailed(e) => format!("daemon failed to start: {e}"),
+        e => format!("framework: {e}"),
+    })
 }
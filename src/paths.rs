@@ -0,0 +1,141 @@
+use std::{
+    fs::DirBuilder,
+    io::ErrorKind,
+    os::unix::fs::{DirBuilderExt as _, MetadataExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
+};
+
+const RUNTIME_SUBDIR: &str = "pidtree_mon";
+const SOCKET_FILENAME: &str = "pidtree_mon.sock";
+const PID_FILENAME: &str = "pidtree_mon.pid";
+
+/// Locations of the files used to find and communicate with the daemon.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paths {
+    /// The UNIX socket the daemon listens on.
+    pub socket: PathBuf,
+    /// The PID file of the daemon.
+    pub pid_file: PathBuf,
+}
+
+impl Paths {
+    /// Determine the paths, using the provided ones if given, or the default ones otherwise.
+    ///
+    /// The default paths are located in a per-user runtime directory, which is
+    /// `$XDG_RUNTIME_DIR/pidtree_mon` or, if `XDG_RUNTIME_DIR` is not set,
+    /// `/tmp/pidtree_mon-<uid>`. The directory is created if it does not exist and it must be owned
+    /// by the current user and not accessible by anyone else.
+    ///
+    /// Relative paths are resolved against the current directory, as the daemon changes its
+    /// directory to `/`.
+    pub fn resolve(socket: Option<PathBuf>, pid_file: Option<PathBuf>) -> Result<Self, String> {
+        let socket = socket.map(absolute).transpose()?;
+        let pid_file = pid_file.map(absolute).transpose()?;
+        let default_dir = || -> Result<PathBuf, String> {
+            let uid = unsafe { libc::getuid() };
+            let dir = match std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+                Some(runtime_dir) => Path::new(&runtime_dir).join(RUNTIME_SUBDIR),
+                None => std::env::temp_dir().join(format!("{RUNTIME_SUBDIR}-{uid}")),
+            };
+            ensure_private_dir(&dir, uid)?;
+            Ok(dir)
+        };
+        let (socket, pid_file) = match (socket, pid_file) {
+            (Some(socket), Some(pid_file)) => (socket, pid_file),
+            (socket, pid_file) => {
+                let dir = default_dir()?;
+                (
+                    socket.unwrap_or_else(|| dir.join(SOCKET_FILENAME)),
+                    pid_file.unwrap_or_else(|| dir.join(PID_FILENAME)),
+                )
+            }
+        };
+        Ok(Self { socket, pid_file })
+    }
+}
+
+/// Make `path` absolute by prepending the current directory if it is relative.
+pub fn absolute(path: PathBuf) -> Result<PathBuf, String> {
+    if path.is_absolute() {
+        return Ok(path);
+    }
+    let dir = std::env::current_dir()
+        .map_err(|e| format!("cannot resolve relative path {}: {e}", path.display()))?;
+    Ok(dir.join(path))
+}
+
+/// Create a directory accessible only by the user, or make sure an existing one is such.
+fn ensure_private_dir(dir: &Path, uid: u32) -> Result<(), String> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => Err(format!("cannot create directory {}: {e}", dir.display()))?,
+    }
+    // symlink_metadata, so that a symlink planted by someone else is not followed
+    let meta = dir
+        .symlink_metadata()
+        .map_err(|e| format!("cannot access directory {}: {e}", dir.display()))?;
+    if !meta.is_dir() {
+        Err(format!("{} is not a directory", dir.display()))?
+    }
+    if meta.uid() != uid {
+        Err(format!(
+            "directory {} is owned by another user",
+            dir.display()
+        ))?
+    }
+    if meta.permissions().mode() & 0o077 != 0 {
+        Err(format!(
+            "directory {} is accessible by other users",
+            dir.display()
+        ))?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_provided_paths() {
+        let paths = Paths::resolve(Some("/a/b.sock".into()), Some("/c/d.pid".into())).unwrap();
+        assert_eq!(
+            paths,
+            Paths {
+                socket: "/a/b.sock".into(),
+                pid_file: "/c/d.pid".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn resolves_relative_paths() {
+        let paths = Paths::resolve(Some("b.sock".into()), Some("/c/d.pid".into())).unwrap();
+        let dir = std::env::current_dir().unwrap();
+        assert_eq!(paths.socket, dir.join("b.sock"));
+        assert_eq!(paths.pid_file, Path::new("/c/d.pid"));
+    }
+
+    #[test]
+    fn checks_private_dir() {
+        let uid = unsafe { libc::getuid() };
+        let dir = std::env::temp_dir().join(format!("pidtree_mon-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        ensure_private_dir(&dir, uid).unwrap();
+        assert_eq!(dir.metadata().unwrap().permissions().mode() & 0o777, 0o700);
+        // already existing is fine
+        ensure_private_dir(&dir, uid).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(ensure_private_dir(&dir, uid).is_err());
+        std::fs::remove_dir(&dir).unwrap();
+        // a symlink to a private directory is not fine
+        let target = dir.with_extension("target");
+        let _ = std::fs::remove_dir_all(&target);
+        ensure_private_dir(&target, uid).unwrap();
+        std::os::unix::fs::symlink(&target, &dir).unwrap();
+        assert!(ensure_private_dir(&dir, uid).is_err());
+        std::fs::remove_file(&dir).unwrap();
+        std::fs::remove_dir(&target).unwrap();
+    }
+}
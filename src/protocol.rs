@@ -0,0 +1,557 @@
+use std::time::Duration;
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+/// The version of the protocol, incremented on each incompatible change.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// The capability of a daemon that measures the proportional set size of processes.
+pub const CAPABILITY_PSS: &str = "pss";
+
+/// The magic number starting each handshake, which allows to tell a client speaking this protocol
+/// apart from one speaking the original headerless protocol.
+const MAGIC: u32 = 0x91d7_4ee0;
+
+/// The maximum accepted length of a string or list, to avoid allocating absurd amounts of memory
+/// when talking to a misbehaving peer.
+const MAX_LEN: u32 = 1 << 16;
+
+/// The first message sent by each side of the connection.
+///
+/// The client sends its hello first and the daemon always responds with its own hello, even if the
+/// versions are incompatible, so that the client can report the problem. The layout of this
+/// message must never change.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    /// The version of the protocol spoken by the sender.
+    pub version: u32,
+    /// The version of the sender's package.
+    pub package_version: String,
+    /// The optional features supported (daemon) or required (client) by the sender.
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// Create a hello of this version of the protocol.
+    pub fn new(capabilities: Vec<String>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            package_version: env!("CARGO_PKG_VERSION").to_owned(),
+            capabilities,
+        }
+    }
+
+    /// Check if the sender speaks the same version of the protocol.
+    pub fn is_compatible(&self) -> bool {
+        self.version == PROTOCOL_VERSION
+    }
+
+    pub async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        writer.write_u32(MAGIC).await?;
+        writer.write_u32(self.version).await?;
+        write_string(&mut writer, &self.package_version).await?;
+        write_len(&mut writer, self.capabilities.len()).await?;
+        for capability in &self.capabilities {
+            write_string(&mut writer, capability).await?;
+        }
+        writer.flush().await
+    }
+
+    pub async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        if reader.read_u32().await? != MAGIC {
+            Err(invalid_data("bad handshake magic number"))?
+        }
+        let version = reader.read_u32().await?;
+        let package_version = read_string(&mut reader).await?;
+        let mut capabilities = vec![];
+        for _ in 0..read_len(&mut reader).await? {
+            capabilities.push(read_string(&mut reader).await?);
+        }
+        Ok(Self {
+            version,
+            package_version,
+            capabilities,
+        })
+    }
+}
+
+/// A request sent from a client to the daemon.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// Start streaming statistics of process trees rooted in the given processes, followed by
+    /// those of the processes matching each of `selectors`, averaged over and sent every
+    /// `interval`.
+    ///
+    /// Unless `wait_full` is set, the first update is sent as soon as any statistics are
+    /// available, even if they span less than `interval`.
+    Monitor {
+        roots: Vec<Root>,
+        selectors: Vec<Selector>,
+        interval: Duration,
+        wait_full: bool,
+    },
+    /// Report the status of the daemon.
+    Status,
+    /// Shut the daemon down, disconnecting all clients.
+    Shutdown,
+}
+
+impl Request {
+    pub async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        match self {
+            Request::Monitor {
+                roots,
+                selectors,
+                interval,
+                wait_full,
+            } => {
+                writer.write_u8(1).await?;
+                write_len(&mut writer, roots.len()).await?;
+                for root in roots {
+                    root.write_to(&mut writer).await?;
+                }
+                write_len(&mut writer, selectors.len()).await?;
+                for selector in selectors {
+                    selector.write_to(&mut writer).await?;
+                }
+                write_duration(&mut writer, *interval).await?;
+                writer.write_u8(*wait_full as u8).await?;
+            }
+            Request::Status => writer.write_u8(2).await?,
+            Request::Shutdown => writer.write_u8(3).await?,
+        }
+        writer.flush().await
+    }
+
+    pub async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        match reader.read_u8().await? {
+            1 => {
+                let mut roots = vec![];
+                for _ in 0..read_len(&mut reader).await? {
+                    roots.push(Root::read_from(&mut reader).await?);
+                }
+                let mut selectors = vec![];
+                for _ in 0..read_len(&mut reader).await? {
+                    selectors.push(Selector::read_from(&mut reader).await?);
+                }
+                let interval = read_duration(&mut reader).await?;
+                let wait_full = match reader.read_u8().await? {
+                    0 => false,
+                    1 => true,
+                    tag => Err(invalid_data(&format!("unknown wait tag {tag}")))?,
+                };
+                Ok(Request::Monitor {
+                    roots,
+                    selectors,
+                    interval,
+                    wait_full,
+                })
+            }
+            2 => Ok(Request::Status),
+            3 => Ok(Request::Shutdown),
+            tag => Err(invalid_data(&format!("unknown request {tag}"))),
+        }
+    }
+}
+
+/// The root process of a monitored process tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Root {
+    pub pid: i32,
+    /// The time the process started, in ticks since boot, which tells it apart from any later
+    /// process reusing the PID, or `None` to accept any process with the PID.
+    pub start_time: Option<u64>,
+}
+
+impl Root {
+    async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        writer.write_i32(self.pid).await?;
+        match self.start_time {
+            Some(start_time) => {
+                writer.write_u8(1).await?;
+                writer.write_u64(start_time).await
+            }
+            None => writer.write_u8(0).await,
+        }
+    }
+
+    async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        let pid = reader.read_i32().await?;
+        let start_time = match reader.read_u8().await? {
+            0 => None,
+            1 => Some(reader.read_u64().await?),
+            tag => Err(invalid_data(&format!("unknown start time tag {tag}")))?,
+        };
+        Ok(Self { pid, start_time })
+    }
+}
+
+/// A criterion selecting processes by what they run, resolved by the daemon on every sample.
+///
+/// The statistics of a selector are those of the process trees rooted in all matching processes
+/// which are not descendants of other matching processes.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Selector {
+    /// Processes with the given command name, as in `/proc/<pid>/comm`.
+    Comm(String),
+    /// Processes whose command line, with arguments separated by spaces, matches a regular
+    /// expression.
+    CmdlineRegex(String),
+    /// Processes running the executable at the given path.
+    Exe(String),
+}
+
+impl Selector {
+    async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        let (tag, value) = match self {
+            Selector::Comm(comm) => (1, comm),
+            Selector::CmdlineRegex(regex) => (2, regex),
+            Selector::Exe(exe) => (3, exe),
+        };
+        writer.write_u8(tag).await?;
+        write_string(writer, value).await
+    }
+
+    async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        let tag = reader.read_u8().await?;
+        let value = read_string(&mut reader).await?;
+        match tag {
+            1 => Ok(Selector::Comm(value)),
+            2 => Ok(Selector::CmdlineRegex(value)),
+            3 => Ok(Selector::Exe(value)),
+            tag => Err(invalid_data(&format!("unknown selector {tag}"))),
+        }
+    }
+}
+
+/// A response sent from the daemon to a client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// The state of each requested process tree, in the order of the request, followed by that
+    /// of each selector.
+    Update(Vec<TreeState>),
+    /// The daemon cannot serve the client anymore.
+    Error(String),
+    /// The status of the daemon.
+    Status(DaemonStatus),
+    /// The daemon has stopped sampling and will exit once all clients disconnect.
+    ShuttingDown,
+}
+
+impl Response {
+    pub async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        match self {
+            Response::Update(trees) => {
+                writer.write_u8(1).await?;
+                write_len(&mut writer, trees.len()).await?;
+                for tree in trees {
+                    tree.write_to(&mut writer).await?;
+                }
+            }
+            Response::Error(message) => {
+                writer.write_u8(2).await?;
+                write_string(&mut writer, message).await?;
+            }
+            Response::Status(status) => {
+                writer.write_u8(3).await?;
+                status.write_to(&mut writer).await?;
+            }
+            Response::ShuttingDown => writer.write_u8(4).await?,
+        }
+        writer.flush().await
+    }
+
+    pub async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        match reader.read_u8().await? {
+            1 => {
+                let mut trees = vec![];
+                for _ in 0..read_len(&mut reader).await? {
+                    trees.push(TreeState::read_from(&mut reader).await?);
+                }
+                Ok(Response::Update(trees))
+            }
+            2 => Ok(Response::Error(read_string(&mut reader).await?)),
+            3 => Ok(Response::Status(
+                DaemonStatus::read_from(&mut reader).await?,
+            )),
+            4 => Ok(Response::ShuttingDown),
+            tag => Err(invalid_data(&format!("unknown response {tag}"))),
+        }
+    }
+}
+
+/// The state of a single process tree, as sent to clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TreeState {
+    /// The root process is running.
+    Alive(TreeStats),
+    /// The root process is not running anymore, or its PID has been reused by another process.
+    /// For a selector, no process matches it.
+    Gone,
+}
+
+impl TreeState {
+    /// The statistics of the tree, all of them unknown if it is gone.
+    pub fn stats(&self) -> TreeStats {
+        match self {
+            TreeState::Alive(stats) => *stats,
+            TreeState::Gone => TreeStats::MISSING,
+        }
+    }
+
+    async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        match self {
+            TreeState::Alive(stats) => {
+                writer.write_u8(1).await?;
+                stats.write_to(writer).await
+            }
+            TreeState::Gone => writer.write_u8(2).await,
+        }
+    }
+
+    async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        match reader.read_u8().await? {
+            1 => Ok(TreeState::Alive(TreeStats::read_from(reader).await?)),
+            2 => Ok(TreeState::Gone),
+            tag => Err(invalid_data(&format!("unknown tree state {tag}"))),
+        }
+    }
+}
+
+/// Statistics of a single process tree, as sent to clients.
+///
+/// Values which are unknown (for example because the process tree does not exist) are `NaN`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TreeStats {
+    /// The CPU load, scaled per-core.
+    pub load: f32,
+    /// The average CPU load since the root process of the tree started, scaled per-core.
+    pub lifetime: f32,
+    /// The total resident set size of all processes in the tree, in bytes.
+    pub rss: f32,
+    /// The total proportional set size of all processes in the tree, in bytes.
+    pub pss: f32,
+    /// The number of bytes per second read from storage by all processes in the tree.
+    pub io_read: f32,
+    /// The number of bytes per second written to storage by all processes in the tree.
+    pub io_write: f32,
+    /// The number of processes in the tree.
+    pub procs: f32,
+    /// The number of threads of all processes in the tree.
+    pub threads: f32,
+    /// The number of processes in the tree which cannot be read, because `/proc` is mounted with
+    /// `hidepid`, making the other statistics incomplete.
+    ///
+    /// Only the topmost hidden processes are counted, as their descendants are hidden as well.
+    pub hidden: f32,
+}
+
+impl TreeStats {
+    /// Statistics of a process tree that could not be found.
+    pub const MISSING: Self = Self {
+        load: f32::NAN,
+        lifetime: f32::NAN,
+        rss: f32::NAN,
+        pss: f32::NAN,
+        io_read: f32::NAN,
+        io_write: f32::NAN,
+        procs: f32::NAN,
+        threads: f32::NAN,
+        hidden: f32::NAN,
+    };
+
+    async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        writer.write_f32(self.load).await?;
+        writer.write_f32(self.lifetime).await?;
+        writer.write_f32(self.rss).await?;
+        writer.write_f32(self.pss).await?;
+        writer.write_f32(self.io_read).await?;
+        writer.write_f32(self.io_write).await?;
+        writer.write_f32(self.procs).await?;
+        writer.write_f32(self.threads).await?;
+        writer.write_f32(self.hidden).await
+    }
+
+    async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        Ok(Self {
+            load: reader.read_f32().await?,
+            lifetime: reader.read_f32().await?,
+            rss: reader.read_f32().await?,
+            pss: reader.read_f32().await?,
+            io_read: reader.read_f32().await?,
+            io_write: reader.read_f32().await?,
+            procs: reader.read_f32().await?,
+            threads: reader.read_f32().await?,
+            hidden: reader.read_f32().await?,
+        })
+    }
+}
+
+/// The status of the daemon.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DaemonStatus {
+    /// The time since the daemon was started.
+    pub uptime: Duration,
+    /// The number of connected monitoring clients.
+    pub clients: u32,
+    /// The interval between consecutive samples.
+    pub interval: Duration,
+    /// The time it took to take the last sample.
+    pub last_sample_duration: Duration,
+}
+
+impl DaemonStatus {
+    async fn write_to(&self, mut writer: impl AsyncWriteExt + Unpin) -> io::Result<()> {
+        write_duration(&mut writer, self.uptime).await?;
+        writer.write_u32(self.clients).await?;
+        write_duration(&mut writer, self.interval).await?;
+        write_duration(&mut writer, self.last_sample_duration).await
+    }
+
+    async fn read_from(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Self> {
+        Ok(Self {
+            uptime: read_duration(&mut reader).await?,
+            clients: reader.read_u32().await?,
+            interval: read_duration(&mut reader).await?,
+            last_sample_duration: read_duration(&mut reader).await?,
+        })
+    }
+}
+
+async fn write_duration(
+    mut writer: impl AsyncWriteExt + Unpin,
+    duration: Duration,
+) -> io::Result<()> {
+    writer.write_u64(duration.as_micros() as u64).await
+}
+
+async fn read_duration(mut reader: impl AsyncReadExt + Unpin) -> io::Result<Duration> {
+    Ok(Duration::from_micros(reader.read_u64().await?))
+}
+
+async fn write_len(mut writer: impl AsyncWriteExt + Unpin, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len)
+        .ok()
+        .filter(|len| *len <= MAX_LEN)
+        .ok_or_else(|| invalid_data("too long to send"))?;
+    writer.write_u32(len).await
+}
+
+async fn read_len(mut reader: impl AsyncReadExt + Unpin) -> io::Result<u32> {
+    let len = reader.read_u32().await?;
+    if len > MAX_LEN {
+        Err(invalid_data("received length too long"))?
+    }
+    Ok(len)
+}
+
+async fn write_string(mut writer: impl AsyncWriteExt + Unpin, s: &str) -> io::Result<()> {
+    write_len(&mut writer, s.len()).await?;
+    writer.write_all(s.as_bytes()).await
+}
+
+async fn read_string(mut reader: impl AsyncReadExt + Unpin) -> io::Result<String> {
+    let mut buf = vec![0; read_len(&mut reader).await? as usize];
+    reader.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|_| invalid_data("string is not utf-8"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hello_roundtrip() {
+        let hello = Hello::new(vec![CAPABILITY_PSS.to_owned()]);
+        let mut buf = vec![];
+        hello.write_to(&mut buf).await.unwrap();
+        let read = Hello::read_from(&buf[..]).await.unwrap();
+        assert_eq!(read, hello);
+        assert!(read.is_compatible());
+    }
+
+    #[tokio::test]
+    async fn rejects_headerless_protocol() {
+        // the original protocol started with raw PIDs
+        let mut buf = vec![];
+        buf.write_i32(1234).await.unwrap();
+        buf.write_i32(5678).await.unwrap();
+        assert!(Hello::read_from(&buf[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn request_response_roundtrip() {
+        for request in [
+            Request::Monitor {
+                roots: vec![
+                    Root {
+                        pid: 1,
+                        start_time: Some(0),
+                    },
+                    Root {
+                        pid: 2,
+                        start_time: None,
+                    },
+                    Root {
+                        pid: 3,
+                        start_time: Some(123456789),
+                    },
+                ],
+                selectors: vec![
+                    Selector::Comm("firefox".to_owned()),
+                    Selector::CmdlineRegex("cargo (build|test)".to_owned()),
+                    Selector::Exe("/usr/bin/rustc".to_owned()),
+                ],
+                interval: Duration::from_millis(250),
+                wait_full: true,
+            },
+            Request::Status,
+            Request::Shutdown,
+        ] {
+            let mut buf = vec![];
+            request.write_to(&mut buf).await.unwrap();
+            assert_eq!(Request::read_from(&buf[..]).await.unwrap(), request);
+        }
+
+        let stats = TreeStats {
+            load: 1.5,
+            lifetime: 0.25,
+            rss: 1024.0,
+            pss: 512.0,
+            io_read: 0.0,
+            io_write: 4096.0,
+            procs: 3.0,
+            threads: 7.0,
+            hidden: 1.0,
+        };
+        for response in [
+            Response::Update(vec![
+                TreeState::Alive(stats),
+                TreeState::Gone,
+                TreeState::Alive(stats),
+            ]),
+            Response::Error("failed".to_owned()),
+            Response::Status(DaemonStatus {
+                uptime: Duration::from_secs(3600),
+                clients: 2,
+                interval: Duration::from_millis(1000),
+                last_sample_duration: Duration::from_micros(12345),
+            }),
+            Response::ShuttingDown,
+        ] {
+            let mut buf = vec![];
+            response.write_to(&mut buf).await.unwrap();
+            assert_eq!(Response::read_from(&buf[..]).await.unwrap(), response);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_frames() {
+        assert!(Request::read_from(&[0xff][..]).await.is_err());
+        assert!(Response::read_from(&[0xff][..]).await.is_err());
+    }
+}
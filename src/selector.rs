@@ -0,0 +1,201 @@
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+
+use crate::{protocol::Selector, snapshot::ProcessInfo};
+
+/// A [Selector] prepared for matching processes.
+pub struct Matcher {
+    selector: Selector,
+    test: Test,
+}
+
+enum Test {
+    Comm(String),
+    Cmdline(Regex),
+    Exe(String),
+}
+
+impl Matcher {
+    /// Prepare `selector`, which fails if it contains an invalid regular expression.
+    pub fn new(selector: &Selector) -> Result<Self, String> {
+        let test = match selector {
+            Selector::Comm(comm) => Test::Comm(comm.clone()),
+            Selector::CmdlineRegex(regex) => Test::Cmdline(
+                Regex::new(regex).map_err(|e| format!("invalid command line regex: {e}"))?,
+            ),
+            Selector::Exe(exe) => Test::Exe(exe.clone()),
+        };
+        Ok(Self {
+            selector: selector.clone(),
+            test,
+        })
+    }
+
+    /// Check if matching requires the command line and executable of each process, see
+    /// [ProcessInfo::cmdline].
+    pub fn needs_commands(&self) -> bool {
+        !matches!(self.test, Test::Comm(_))
+    }
+
+    /// Check if the process matches.
+    ///
+    /// Processes in `own`, see [find_own_processes], never match a command line regex, as their
+    /// command lines may contain it.
+    fn matches(&self, process: &ProcessInfo, own: &HashSet<i32>) -> bool {
+        match &self.test {
+            Test::Comm(comm) => process.comm == *comm,
+            Test::Cmdline(regex) => !own.contains(&process.pid) && regex.is_match(&process.cmdline),
+            Test::Exe(exe) => process.exe == *exe,
+        }
+    }
+}
+
+/// Find the processes of pidtree_mon itself: those running it, the client processes `clients`,
+/// and the ancestors of clients that mention it in their command lines, like the shell running
+/// `sh -c 'pidtree_mon --cmdline-regex cargo'`.
+fn find_own_processes(processes: &[ProcessInfo], clients: &[i32]) -> HashSet<i32> {
+    let name = env!("CARGO_BIN_NAME");
+    let by_pid: HashMap<_, _> = processes.iter().map(|p| (p.pid, p)).collect();
+    let mut own: HashSet<_> = processes
+        .iter()
+        .filter(|p| p.comm == name)
+        .map(|p| p.pid)
+        .chain(clients.iter().copied())
+        .collect();
+    for client in clients {
+        let mut chain = HashSet::new();
+        let mut ancestor = by_pid.get(client).map(|p| p.ppid);
+        while let Some(process) = ancestor.and_then(|pid| by_pid.get(&pid)) {
+            if !process.cmdline.contains(name) || !chain.insert(process.pid) {
+                break;
+            }
+            ancestor = Some(process.ppid);
+        }
+        own.extend(chain);
+    }
+    own
+}
+
+/// Find the processes matching each selector of `matchers`, except those which are descendants of
+/// other matching processes, as they belong to their trees, and those of pidtree_mon and its
+/// connected client processes `clients`, see [find_own_processes].
+pub fn select(
+    processes: &[ProcessInfo],
+    matchers: &[Matcher],
+    clients: &[i32],
+) -> HashMap<Selector, Vec<i32>> {
+    let parents: HashMap<_, _> = processes.iter().map(|p| (p.pid, p.ppid)).collect();
+    let own = find_own_processes(processes, clients);
+    matchers
+        .iter()
+        .map(|matcher| {
+            let matching: HashSet<_> = processes
+                .iter()
+                .filter(|p| matcher.matches(p, &own))
+                .map(|p| p.pid)
+                .collect();
+            // Parent links may form a cycle if PIDs were reused while reading the processes.
+            let is_descendant = |pid| {
+                let mut visited = HashSet::from([pid]);
+                let mut ancestor = parents.get(&pid);
+                while let Some(id) = ancestor.filter(|id| visited.insert(**id)) {
+                    if matching.contains(id) {
+                        return true;
+                    }
+                    ancestor = parents.get(id);
+                }
+                false
+            };
+            let mut topmost: Vec<_> = matching
+                .iter()
+                .copied()
+                .filter(|pid| !is_descendant(*pid))
+                .collect();
+            topmost.sort();
+            (matcher.selector.clone(), topmost)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::fixture;
+
+    fn process(pid: i32, ppid: i32, comm: &str, cmdline: &str, exe: &str) -> ProcessInfo {
+        ProcessInfo {
+            cmdline: cmdline.to_owned(),
+            exe: exe.to_owned(),
+            ..fixture::process(pid, ppid, comm)
+        }
+    }
+
+    #[test]
+    fn selects_topmost_matching_processes() {
+        let processes = [
+            process(1, 0, "init", "/sbin/init", "/sbin/init"),
+            process(10, 1, "bash", "bash", "/usr/bin/bash"),
+            process(11, 10, "cargo", "cargo build", "/usr/bin/cargo"),
+            process(12, 11, "rustc", "rustc --crate-name a", "/usr/bin/rustc"),
+            process(13, 12, "rustc", "rustc --crate-name b", "/usr/bin/rustc"),
+            process(14, 10, "cargo", "cargo test", "/usr/bin/cargo"),
+            process(20, 1, "rustc", "rustc --crate-name c", "/usr/bin/rustc"),
+            process(
+                30,
+                1,
+                "pidtree_mon",
+                "pidtree_mon --cmdline-regex cargo",
+                "",
+            ),
+            // a client run by a shell, and a client under another name run by bash
+            process(
+                40,
+                1,
+                "sh",
+                "sh -c pidtree_mon --cmdline-regex cargo",
+                "/bin/sh",
+            ),
+            process(
+                41,
+                40,
+                "pidtree_mon",
+                "pidtree_mon --cmdline-regex cargo",
+                "",
+            ),
+            process(42, 10, "mon", "./mon --cmdline-regex cargo", ""),
+        ];
+        let selectors = [
+            Selector::Comm("rustc".to_owned()),
+            Selector::CmdlineRegex("^cargo (build|test)".to_owned()),
+            Selector::CmdlineRegex("cargo".to_owned()),
+            Selector::Exe("/usr/bin/bash".to_owned()),
+            Selector::Comm("firefox".to_owned()),
+        ];
+        let matchers: Vec<_> = selectors.iter().map(|s| Matcher::new(s).unwrap()).collect();
+        let selected = select(&processes, &matchers, &[41, 42]);
+        let selected: Vec<_> = selectors.iter().map(|s| selected[s].clone()).collect();
+        assert_eq!(
+            selected,
+            vec![vec![12, 20], vec![11, 14], vec![11, 14], vec![10], vec![]]
+        );
+        assert!(!matchers[0].needs_commands());
+        assert!(matchers[1].needs_commands());
+        assert!(Matcher::new(&Selector::CmdlineRegex("(".to_owned())).is_err());
+    }
+
+    #[test]
+    fn selects_under_parent_cycles() {
+        // 10 and 11 are each other's parents after their PIDs were reused while being read
+        let processes = [
+            process(1, 0, "init", "/sbin/init", "/sbin/init"),
+            process(10, 11, "sh", "sh", "/bin/sh"),
+            process(11, 10, "sh", "sh", "/bin/sh"),
+            process(12, 10, "cc", "cc a.c", "/usr/bin/cc"),
+            process(13, 12, "cc", "cc b.c", "/usr/bin/cc"),
+        ];
+        let selector = Selector::Comm("cc".to_owned());
+        let selected = select(&processes, &[Matcher::new(&selector).unwrap()], &[]);
+        assert_eq!(selected[&selector], vec![12]);
+    }
+}
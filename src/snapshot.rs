@@ -0,0 +1,774 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use procfs::{
+    process::Process, Current as _, FromRead as _, Meminfo, ProcError, ProcResult, Uptime,
+};
+
+/// The first word of a recording, followed by the format version and the [System] properties.
+const RECORDING_MAGIC: &str = "pidtree_mon-recording";
+const RECORDING_VERSION: u32 = 3;
+
+/// The raw information about a process read from `/proc`, from which all statistics are computed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    /// The PID of the parent process, 0 for none.
+    pub ppid: i32,
+    /// The time the process started, in ticks since boot.
+    pub start_time: u64,
+    /// The time in ticks spent by the process in user and kernel mode since its creation.
+    pub self_ticks: u64,
+    /// The time in ticks spent by the process's waited-for children in user and kernel mode,
+    /// including their own waited-for children.
+    pub children_ticks: u64,
+    /// The resident set size in bytes.
+    pub rss: u64,
+    /// The proportional set size in bytes, or zero if not measured or not readable.
+    pub pss: u64,
+    /// The total number of bytes read from storage by the process and its waited-for children, or
+    /// zero if not readable.
+    pub read_bytes: u64,
+    /// The total number of bytes written to storage by the process and its waited-for children,
+    /// or zero if not readable.
+    pub write_bytes: u64,
+    /// The number of threads.
+    pub threads: u64,
+    /// The number of children which cannot be read, because `/proc` is mounted with `hidepid`
+    /// and they belong to other users, or zero if the visibility of processes is not restricted.
+    pub hidden_children: u64,
+    /// The command name, at most 15 bytes long.
+    pub comm: String,
+    /// The command line with arguments separated by spaces, or empty if not read or not readable,
+    /// e.g. for kernel threads.
+    pub cmdline: String,
+    /// The path of the executable, or empty if not read or not readable.
+    pub exe: String,
+}
+
+/// The information about all processes at a single point in time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// The UNIX timestamp in seconds.
+    pub time: f64,
+    /// The time since boot in ticks, used to measure the time between snapshots and the time since
+    /// a process started.
+    pub uptime: u64,
+    pub processes: Vec<ProcessInfo>,
+}
+
+impl Snapshot {
+    /// Read the information about all processes from `root`, which is normally `/proc`.
+    ///
+    /// The proportional set size of each process is only read if `measure_pss` is set, as it is
+    /// considerably more expensive than reading `stat` alone.
+    ///
+    /// The command line and executable of each process are only read if `read_commands` is set,
+    /// as they are only needed to select processes by them.
+    ///
+    /// Processes which cannot be read, for example because they have just exited, are skipped.
+    /// If `root` is mounted with `hidepid`, the children of each process are read to count those
+    /// hidden from this process, see [ProcessInfo::hidden_children].
+    pub fn capture(
+        root: &Path,
+        measure_pss: bool,
+        read_commands: bool,
+    ) -> Result<Self, SnapshotError> {
+        let uptime = Uptime::from_file(root.join("uptime"))
+            .map_err(|e| SnapshotError::new("uptime", e))?
+            .uptime;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let all_procs = procfs::process::all_processes_with_root(root)
+            .map_err(|e| SnapshotError::new("processes", e))?;
+        let page_size = procfs::page_size();
+        let hidepid = hidepid(root);
+        let mut processes: Vec<_> = all_procs
+            .filter_map(|prc| {
+                let prc = prc.ok()?;
+                let stat = prc.stat().ok()?;
+                // Kernel threads and processes of other users have no readable smaps_rollup, so
+                // they simply do not contribute to PSS.
+                let pss = measure_pss
+                    .then(|| prc.smaps_rollup().ok())
+                    .flatten()
+                    .and_then(|rollup| {
+                        rollup
+                            .memory_map_rollup
+                            .iter()
+                            .find_map(|map| map.extension.map.get("Pss").copied())
+                    })
+                    .unwrap_or(0);
+                // Only readable for processes of the same user, others do not contribute to I/O.
+                let io = prc.io().ok();
+                let (cmdline, exe) = if read_commands {
+                    (
+                        prc.cmdline().map(|args| args.join(" ")).unwrap_or_default(),
+                        prc.exe()
+                            .map(|exe| exe.to_string_lossy().into_owned())
+                            .unwrap_or_default(),
+                    )
+                } else {
+                    Default::default()
+                };
+                let children = if hidepid != Hidepid::Off {
+                    read_children(&prc)
+                } else {
+                    vec![]
+                };
+                let info = ProcessInfo {
+                    pid: stat.pid,
+                    ppid: stat.ppid,
+                    start_time: stat.starttime,
+                    self_ticks: stat.utime + stat.stime,
+                    children_ticks: (stat.cutime + stat.cstime) as u64,
+                    rss: stat.rss * page_size,
+                    pss,
+                    read_bytes: io.as_ref().map(|io| io.read_bytes).unwrap_or(0),
+                    write_bytes: io.as_ref().map(|io| io.write_bytes).unwrap_or(0),
+                    threads: stat.num_threads as u64,
+                    hidden_children: 0,
+                    comm: stat.comm,
+                    cmdline,
+                    exe,
+                };
+                Some((info, children))
+            })
+            .collect();
+        let visible: HashSet<_> = processes.iter().map(|(info, _)| info.pid).collect();
+        for (info, children) in &mut processes {
+            let mut hidden: Vec<_> = children
+                .iter()
+                .copied()
+                .filter(|child| !visible.contains(child))
+                .filter(|child| {
+                    match Process::new_with_root(root.join(child.to_string()))
+                        .and_then(|p| p.stat())
+                    {
+                        // started after the listing, so not hidden, just missed
+                        Ok(_) => false,
+                        Err(ProcError::PermissionDenied(_)) => true,
+                        // exited since it was listed, unless hidepid removes it from `root`
+                        Err(ProcError::NotFound(_)) => hidepid == Hidepid::Invisible,
+                        Err(_) => false,
+                    }
+                })
+                .collect();
+            // An invisible child which has exited in the meantime is no longer a child.
+            if !hidden.is_empty() && hidepid == Hidepid::Invisible {
+                let children = Process::new_with_root(root.join(info.pid.to_string()))
+                    .map(|prc| read_children(&prc))
+                    .unwrap_or_default();
+                hidden.retain(|child| children.contains(child));
+            }
+            info.hidden_children = hidden.len() as u64;
+        }
+        let processes = processes.into_iter().map(|(info, _)| info).collect();
+        Ok(Self {
+            time,
+            // rounded, as the product of two-decimal seconds may fall just short of a whole tick
+            uptime: (uptime * procfs::ticks_per_second() as f64).round() as u64,
+            processes,
+        })
+    }
+}
+
+/// How a `/proc` mounted with `hidepid` hides the processes of other users.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Hidepid {
+    /// All processes can be read.
+    Off,
+    /// The processes of other users are listed, but cannot be read.
+    NoAccess,
+    /// The processes of other users are not listed and cannot be found.
+    Invisible,
+}
+
+/// Check if `root` is a `/proc` mounted with `hidepid`, and how it hides processes.
+fn hidepid(root: &Path) -> Hidepid {
+    let Ok(mounts) = Process::new_with_root(root.join("self")).and_then(|p| p.mountinfo()) else {
+        return Hidepid::Off;
+    };
+    mounts
+        .into_iter()
+        .filter(|mount| mount.fs_type == "proc" && mount.mount_point == root)
+        .filter_map(|mount| mount.super_options.get("hidepid").cloned().flatten())
+        .map(|hidepid| match hidepid.as_str() {
+            "0" | "off" => Hidepid::Off,
+            "1" | "noaccess" => Hidepid::NoAccess,
+            _ => Hidepid::Invisible,
+        })
+        .max()
+        .unwrap_or(Hidepid::Off)
+}
+
+/// Read the PIDs of the children of all threads of a process, or none if they cannot be read.
+fn read_children(prc: &Process) -> Vec<i32> {
+    let children = || -> ProcResult<Vec<i32>> {
+        let mut children = vec![];
+        for task in prc.tasks()? {
+            children.extend(task?.children()?.into_iter().map(|pid| pid as i32));
+        }
+        Ok(children)
+    };
+    children().unwrap_or_default()
+}
+
+/// An error which prevented taking a snapshot of all processes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotError {
+    /// Reading failed in a way which may not happen again, e.g. because of running out of file
+    /// descriptors or memory.
+    Transient(String),
+    /// The information is not available, e.g. because `/proc` is not mounted.
+    Unavailable(String),
+}
+
+impl SnapshotError {
+    fn new(what: &str, e: ProcError) -> Self {
+        let message = format!("cannot read {what}: {e}");
+        match e {
+            ProcError::NotFound(_) | ProcError::PermissionDenied(_) => Self::Unavailable(message),
+            _ => Self::Transient(message),
+        }
+    }
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Transient(message) | SnapshotError::Unavailable(message) => {
+                write!(f, "{message}")
+            }
+        }
+    }
+}
+
+/// A source of snapshots of all processes, from which the sampler computes the statistics.
+pub trait SnapshotSource: Send + 'static {
+    /// Take the next snapshot, or return `None` if there are no more snapshots.
+    fn snapshot(&mut self) -> Option<Result<Snapshot, SnapshotError>>;
+
+    /// Set whether the following snapshots should include the command line and executable of each
+    /// process, if the source can read them.
+    fn read_commands(&mut self, _read: bool) {}
+}
+
+/// A `/proc`-shaped directory, see [Snapshot::capture].
+///
+/// Apart from the live system, this can be a fixture with just the files that are read: `uptime`
+/// and `<pid>/stat`, and optionally `<pid>/io` and `<pid>/smaps_rollup`.
+pub struct Procfs {
+    pub root: PathBuf,
+    pub measure_pss: bool,
+    pub read_commands: bool,
+}
+
+impl Procfs {
+    /// The live system.
+    pub fn live(measure_pss: bool) -> Self {
+        Self {
+            root: PathBuf::from("/proc"),
+            measure_pss,
+            read_commands: false,
+        }
+    }
+}
+
+impl SnapshotSource for Procfs {
+    fn snapshot(&mut self) -> Option<Result<Snapshot, SnapshotError>> {
+        Some(Snapshot::capture(
+            &self.root,
+            self.measure_pss,
+            self.read_commands,
+        ))
+    }
+
+    fn read_commands(&mut self, read: bool) {
+        self.read_commands = read;
+    }
+}
+
+/// The properties of the system snapshots were captured on, needed to interpret them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct System {
+    pub ticks_per_second: u64,
+    /// The number of cores.
+    pub cores: f32,
+    /// The total memory in bytes.
+    pub memory: f32,
+    /// Whether the proportional set size of processes was measured.
+    pub measure_pss: bool,
+}
+
+impl System {
+    /// The properties of the current system.
+    pub fn current(measure_pss: bool) -> Self {
+        Self {
+            ticks_per_second: procfs::ticks_per_second(),
+            cores: num_cpus::get() as f32,
+            memory: Meminfo::current()
+                .map(|m| m.mem_total as f32)
+                .unwrap_or(f32::NAN),
+            measure_pss,
+        }
+    }
+}
+
+/// Consecutive snapshots of all processes, which can be replayed to reproduce the statistics
+/// computed from them.
+#[derive(Debug, PartialEq)]
+pub struct Recording {
+    pub system: System,
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl Recording {
+    /// Read a recording written by [Recorder].
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("cannot open recording {}: {e}", path.display()))?;
+        Self::read_from(BufReader::new(file))
+            .map_err(|e| format!("cannot read recording {}: {e}", path.display()))
+    }
+
+    fn read_from(reader: impl BufRead) -> Result<Self, String> {
+        let mut lines = reader.lines().enumerate();
+        let mut next_line = || -> Result<Option<(usize, Vec<String>)>, String> {
+            let Some((n, line)) = lines.next() else {
+                return Ok(None);
+            };
+            let line = line.map_err(|e| format!("{e}"))?;
+            Ok(Some((
+                n + 1,
+                line.split_whitespace().map(str::to_owned).collect(),
+            )))
+        };
+        let (_, header) = next_line()?.ok_or("empty file")?;
+        let system = match header.as_slice() {
+            [magic, version, ticks_per_second, cores, memory, measure_pss]
+                if magic == RECORDING_MAGIC =>
+            {
+                if parse::<u32>(version)? != RECORDING_VERSION {
+                    Err(format!("unsupported version {version}"))?
+                }
+                System {
+                    ticks_per_second: parse(ticks_per_second)?,
+                    cores: parse(cores)?,
+                    memory: parse(memory)?,
+                    measure_pss: parse::<u8>(measure_pss)? != 0,
+                }
+            }
+            _ => Err("not a recording")?,
+        };
+        let mut snapshots: Vec<Snapshot> = vec![];
+        while let Some((n, words)) = next_line()? {
+            let at_line = |e| format!("line {n}: {e}");
+            match words.as_slice() {
+                [snapshot, time, uptime] if snapshot == "snapshot" => {
+                    let uptime = parse(uptime).map_err(at_line)?;
+                    if snapshots.last().is_some_and(|last| last.uptime >= uptime) {
+                        Err(at_line("uptime does not increase".to_owned()))?
+                    }
+                    snapshots.push(Snapshot {
+                        time: parse(time).map_err(at_line)?,
+                        uptime,
+                        processes: vec![],
+                    });
+                }
+                [pid, ppid, start_time, self_ticks, children_ticks, rss, pss, read_bytes, write_bytes, threads, hidden_children, comm, cmdline, exe] =>
+                {
+                    let info = (|| {
+                        Ok::<_, String>(ProcessInfo {
+                            pid: parse(pid)?,
+                            ppid: parse(ppid)?,
+                            start_time: parse(start_time)?,
+                            self_ticks: parse(self_ticks)?,
+                            children_ticks: parse(children_ticks)?,
+                            rss: parse(rss)?,
+                            pss: parse(pss)?,
+                            read_bytes: parse(read_bytes)?,
+                            write_bytes: parse(write_bytes)?,
+                            threads: parse(threads)?,
+                            hidden_children: parse(hidden_children)?,
+                            comm: unescape(comm)?,
+                            cmdline: unescape(cmdline)?,
+                            exe: unescape(exe)?,
+                        })
+                    })()
+                    .map_err(at_line)?;
+                    snapshots
+                        .last_mut()
+                        .ok_or_else(|| at_line("process outside of a snapshot".to_owned()))?
+                        .processes
+                        .push(info);
+                }
+                [] => {}
+                _ => Err(at_line("unexpected number of values".to_owned()))?,
+            }
+        }
+        Ok(Self { system, snapshots })
+    }
+}
+
+/// Writes consecutive snapshots to a file, so that they can be replayed as a [Recording].
+pub struct Recorder<W: Write = BufWriter<File>> {
+    writer: W,
+}
+
+impl Recorder {
+    /// Create a new recording at `path`, replacing an existing file.
+    pub fn create(path: &Path, system: &System) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("cannot create recording {}: {e}", path.display()))?;
+        Self::new(BufWriter::new(file), system)
+            .map_err(|e| format!("cannot write recording {}: {e}", path.display()))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    fn new(mut writer: W, system: &System) -> std::io::Result<Self> {
+        writeln!(
+            writer,
+            "{RECORDING_MAGIC} {RECORDING_VERSION} {} {} {} {}",
+            system.ticks_per_second, system.cores, system.memory, system.measure_pss as u8
+        )?;
+        writer.flush()?;
+        Ok(Self { writer })
+    }
+
+    /// Append `snapshot` to the recording.
+    pub fn record(&mut self, snapshot: &Snapshot) -> std::io::Result<()> {
+        let w = &mut self.writer;
+        writeln!(w, "snapshot {} {}", snapshot.time, snapshot.uptime)?;
+        for p in &snapshot.processes {
+            writeln!(
+                w,
+                "{} {} {} {} {} {} {} {} {} {} {} {} {} {}",
+                p.pid,
+                p.ppid,
+                p.start_time,
+                p.self_ticks,
+                p.children_ticks,
+                p.rss,
+                p.pss,
+                p.read_bytes,
+                p.write_bytes,
+                p.threads,
+                p.hidden_children,
+                escape(&p.comm),
+                escape(&p.cmdline),
+                escape(&p.exe)
+            )?;
+        }
+        // so that the recording is usable even if the daemon is killed
+        w.flush()
+    }
+}
+
+fn parse<T>(value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("bad value `{value}`: {e}"))
+}
+
+/// Escape a string so that it is a single, non-empty word of a recording.
+///
+/// `%`, whitespace and control characters are written as `%` followed by two hexadecimal digits
+/// of each of their bytes, and a lone `%` stands for the empty string.
+fn escape(value: &str) -> String {
+    if value.is_empty() {
+        return "%".to_owned();
+    }
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '%' || c.is_whitespace() || c.is_control() {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{byte:02x}"));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> Result<String, String> {
+    if value == "%" {
+        return Ok(String::new());
+    }
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("bad escape in `{value}`"))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("`{value}` is not UTF-8"))
+}
+
+/// Writing of fixtures for [Procfs].
+#[cfg(test)]
+pub mod fixture {
+    use super::*;
+
+    /// Write a fixture at `root` with `processes`, `uptime` ticks after boot.
+    pub fn write(root: &Path, uptime: u64, processes: &[ProcessInfo]) {
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root).unwrap();
+        let seconds = uptime as f64 / procfs::ticks_per_second() as f64;
+        std::fs::write(root.join("uptime"), format!("{seconds:.2} 0.00\n")).unwrap();
+        for p in processes {
+            let dir = root.join(p.pid.to_string());
+            std::fs::create_dir(&dir).unwrap();
+            let mut stat = vec![0; 34];
+            stat[0] = p.ppid as u64;
+            stat[10] = p.self_ticks;
+            stat[12] = p.children_ticks;
+            stat[16] = p.threads;
+            stat[18] = p.start_time;
+            stat[20] = p.rss / procfs::page_size();
+            let stat: Vec<_> = stat.iter().map(u64::to_string).collect();
+            let stat = format!("{} ({}) S {}\n", p.pid, p.comm, stat.join(" "));
+            std::fs::write(dir.join("stat"), stat).unwrap();
+            let io = format!(
+                "rchar: 0\nwchar: 0\nsyscr: 0\nsyscw: 0\nread_bytes: {}\nwrite_bytes: {}\n\
+                 cancelled_write_bytes: 0\n",
+                p.read_bytes, p.write_bytes
+            );
+            std::fs::write(dir.join("io"), io).unwrap();
+            let task = dir.join("task").join(p.pid.to_string());
+            std::fs::create_dir_all(&task).unwrap();
+            let children: Vec<_> = processes
+                .iter()
+                .filter(|c| c.ppid == p.pid)
+                .map(|c| c.pid.to_string())
+                .collect();
+            std::fs::write(task.join("children"), children.join(" ")).unwrap();
+            if !p.cmdline.is_empty() {
+                let cmdline: String = p.cmdline.split(' ').map(|arg| format!("{arg}\0")).collect();
+                std::fs::write(dir.join("cmdline"), cmdline).unwrap();
+            }
+            if !p.exe.is_empty() {
+                std::os::unix::fs::symlink(&p.exe, dir.join("exe")).unwrap();
+            }
+        }
+    }
+
+    /// A single-threaded process started at boot without any resource usage, to be adjusted with
+    /// struct update syntax.
+    pub fn process(pid: i32, ppid: i32, comm: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            start_time: 0,
+            self_ticks: 0,
+            children_ticks: 0,
+            rss: 0,
+            pss: 0,
+            read_bytes: 0,
+            write_bytes: 0,
+            threads: 1,
+            hidden_children: 0,
+            comm: comm.to_owned(),
+            cmdline: String::new(),
+            exe: String::new(),
+        }
+    }
+
+    /// Make the fixture at `root` look mounted with the `hidepid` option, as seen by the process
+    /// `pid`.
+    pub fn restrict(root: &Path, pid: i32, hidepid: &str) {
+        std::os::unix::fs::symlink(pid.to_string(), root.join("self")).unwrap();
+        let mountinfo = format!(
+            "22 1 0:5 / {} rw,nosuid - proc proc rw,hidepid={hidepid}\n",
+            root.display()
+        );
+        std::fs::write(root.join(pid.to_string()).join("mountinfo"), mountinfo).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_fixture() {
+        let root =
+            std::env::temp_dir().join(format!("pidtree_mon-test-{}-proc", std::process::id()));
+        let processes = vec![
+            ProcessInfo {
+                self_ticks: 150,
+                children_ticks: 30,
+                rss: 4 * procfs::page_size(),
+                read_bytes: 100,
+                write_bytes: 200,
+                cmdline: "/sbin/init splash".to_owned(),
+                exe: "/usr/lib/systemd/systemd".to_owned(),
+                ..fixture::process(1, 0, "init")
+            },
+            ProcessInfo {
+                start_time: 1500,
+                self_ticks: 5,
+                threads: 4,
+                ..fixture::process(20, 1, "kworker/0:1")
+            },
+        ];
+        fixture::write(&root, 2000, &processes);
+        let mut source = Procfs {
+            root: root.clone(),
+            measure_pss: false,
+            read_commands: true,
+        };
+        let mut snapshot = source.snapshot().unwrap().unwrap();
+        snapshot.processes.sort_by_key(|p| p.pid);
+        assert_eq!(snapshot.uptime, 2000);
+        assert_eq!(snapshot.processes, processes);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(matches!(
+            source.snapshot(),
+            Some(Err(SnapshotError::Unavailable(_)))
+        ));
+    }
+
+    #[test]
+    fn counts_hidden_children() {
+        let root =
+            std::env::temp_dir().join(format!("pidtree_mon-test-{}-hidepid", std::process::id()));
+        let process = |pid, ppid| fixture::process(pid, ppid, "sh");
+        let processes = [
+            process(1, 0),
+            process(20, 1),
+            process(21, 20),
+            process(22, 21),
+            process(23, 20),
+        ];
+        fixture::write(&root, 2000, &processes);
+        let hidden_children = || {
+            let snapshot = Snapshot::capture(&root, false, false).unwrap();
+            let mut hidden: Vec<_> = snapshot
+                .processes
+                .iter()
+                .map(|p| (p.pid, p.hidden_children))
+                .collect();
+            hidden.sort();
+            hidden
+        };
+        assert_eq!(
+            hidden_children(),
+            vec![(1, 0), (20, 0), (21, 0), (22, 0), (23, 0)]
+        );
+        // 21 and its child belong to another user
+        fixture::restrict(&root, 1, "invisible");
+        std::fs::remove_dir_all(root.join("21")).unwrap();
+        std::fs::remove_dir_all(root.join("22")).unwrap();
+        assert_eq!(hidden_children(), vec![(1, 0), (20, 1), (23, 0)]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ignores_exited_children() {
+        let root =
+            std::env::temp_dir().join(format!("pidtree_mon-test-{}-exited", std::process::id()));
+        let process = |pid, ppid| fixture::process(pid, ppid, "sh");
+        let processes = [process(1, 0), process(20, 1), process(21, 20)];
+        fixture::write(&root, 2000, &processes);
+        fixture::restrict(&root, 1, "noaccess");
+        // 21 exits after 20 lists it as a child
+        std::fs::remove_dir_all(root.join("21")).unwrap();
+        let snapshot = Snapshot::capture(&root, false, false).unwrap();
+        let mut hidden: Vec<_> = snapshot
+            .processes
+            .iter()
+            .map(|p| (p.pid, p.hidden_children))
+            .collect();
+        hidden.sort();
+        assert_eq!(hidden, vec![(1, 0), (20, 0)]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reads_recorded_snapshots() {
+        let system = System {
+            ticks_per_second: 100,
+            cores: 4.0,
+            memory: 1024.0,
+            measure_pss: false,
+        };
+        let process = |pid, ppid| ProcessInfo {
+            start_time: 10,
+            self_ticks: 20,
+            children_ticks: 5,
+            rss: 4096,
+            read_bytes: 1,
+            write_bytes: 2,
+            threads: 3,
+            hidden_children: 1,
+            cmdline: "sh -c echo\t100%".to_owned(),
+            ..fixture::process(pid, ppid, "sh")
+        };
+        let snapshots = vec![
+            Snapshot {
+                time: 1_700_000_000.25,
+                uptime: 2000,
+                processes: vec![process(1, 0), process(2, 1)],
+            },
+            Snapshot {
+                time: 1_700_000_001.25,
+                uptime: 2100,
+                processes: vec![],
+            },
+        ];
+        let mut recorder = Recorder::new(vec![], &system).unwrap();
+        for snapshot in &snapshots {
+            recorder.record(snapshot).unwrap();
+        }
+        let recorded = recorder.writer;
+        let text = String::from_utf8(recorded.clone()).unwrap();
+        assert!(text.contains(" 1 sh sh%20-c%20echo%09100%25 %\n"));
+        assert_eq!(
+            Recording::read_from(recorded.as_slice()),
+            Ok(Recording { system, snapshots })
+        );
+        assert!(Recording::read_from("".as_bytes()).is_err());
+        assert!(Recording::read_from("something else".as_bytes()).is_err());
+        let bad = "pidtree_mon-recording 3 100 4 1024 0\n1 0 10 20 0 4096 0 1 2 3 0 sh % %\n";
+        assert_eq!(
+            Recording::read_from(bad.as_bytes()),
+            Err("line 2: process outside of a snapshot".to_owned())
+        );
+        let bad = "pidtree_mon-recording 3 100 4 1024 0\nsnapshot 1.5 200\nsnapshot 2.5 100\n";
+        assert_eq!(
+            Recording::read_from(bad.as_bytes()),
+            Err("line 3: uptime does not increase".to_owned())
+        );
+        // parent cycles, which PIDs reused while reading `/proc` may produce, are left to the sampler
+        let cycle = "pidtree_mon-recording 3 100 4 1024 0\nsnapshot 1.5 200\n\
+                     2 3 10 20 0 4096 0 1 2 3 0 sh % %\n\
+                     3 2 10 20 0 4096 0 1 2 3 0 sh % %\n";
+        assert!(Recording::read_from(cycle.as_bytes()).is_ok());
+    }
+}
@@ -1,86 +1,829 @@
-use std::{collections::HashMap, hash::Hash, ops::Add, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    hash::Hash,
+    ops::Add,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use futures::{never::Never, stream::unfold, StreamExt};
-use log::warn;
+use log::{error, info, warn};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    io::{AsyncWriteExt, BufReader, BufWriter},
     net::UnixStream as TokioUnixStream,
-    sync::broadcast::{self, error::RecvError},
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
     time::{sleep_until, Instant},
 };
 use with_daemon::DaemonControl;
 
+use crate::{
+    protocol::{
+        DaemonStatus, Hello, Request, Response, Root, Selector, TreeState, TreeStats,
+        CAPABILITY_PSS,
+    },
+    selector::{self, Matcher},
+    snapshot::{
+        ProcessInfo, Procfs, Recorder, Recording, Snapshot, SnapshotError, SnapshotSource, System,
+    },
+};
+
+/// The shortest sampling interval, regardless of the intervals requested by clients.
+const MIN_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The maximum time until the next sample after the first one, which only serves as a reference
+/// point, or after one that was skipped.
+const WARMUP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The number of consecutive samples which can be skipped because of transient failures before
+/// sampling fails.
+const MAX_SKIPPED_SAMPLES: u32 = 5;
+
+/// Settings of the worker, determined by the client which spawns the daemon.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The interval between consecutive samples when no clients are connected.
+    pub update_interval: Duration,
+    /// Whether to measure the proportional set size of each process, which is read from
+    /// `/proc/<pid>/smaps_rollup` and is considerably more expensive than reading `stat` alone.
+    pub measure_pss: bool,
+    /// The time without any connected clients after which the daemon exits, or `None` to never
+    /// exit.
+    pub idle_timeout: Option<Duration>,
+    /// A file to record all snapshots to, so that they can be replayed later.
+    pub record: Option<PathBuf>,
+}
+
+/// The statistics of all process trees measured by a single sample.
+struct Tick {
+    /// The consecutive number of the sample
+    seq: u64,
+    /// The time since the previous sample in seconds, over which loads and I/O rates are averaged.
+    seconds: f32,
+    /// The start time of each process and the statistics of the tree rooted in it
+    stats: HashMap<i32, (u64, TreeStats)>,
+    /// The processes selected by each selector requested by clients at the time of the sample, see
+    /// [selector::select]
+    selected: HashMap<Selector, Vec<i32>>,
+}
+
+/// The number of connected clients which requested each interval.
+type Intervals = BTreeMap<Duration, usize>;
+
+/// The number of connected clients which requested each selector.
+type Selectors = BTreeMap<Selector, usize>;
+
+/// The number of connections of each monitoring client process.
+type ClientPids = BTreeMap<i32, usize>;
+
 pub struct Worker {
-    loads: broadcast::Receiver<Arc<HashMap<i32, f32>>>,
-    ctrl: DaemonControl,
+    ticks: broadcast::Receiver<Arc<Tick>>,
+    /// The most recent ticks, spanning at least the longest interval requested by clients, so that
+    /// a new client can be sent statistics right away
+    recent: Arc<Mutex<VecDeque<Arc<Tick>>>>,
+    settings: Settings,
+    started_at: Instant,
+    /// The number of connected monitoring clients
+    clients: Arc<AtomicU32>,
+    /// The intervals requested by connected monitoring clients, the fastest of which is used for
+    /// sampling
+    intervals: watch::Sender<Intervals>,
+    /// The selectors requested by connected monitoring clients, resolved on every sample
+    selectors: watch::Sender<Selectors>,
+    /// The PIDs of connected monitoring clients, which are not selected along with their
+    /// wrappers, see [selector::select]
+    client_pids: watch::Sender<ClientPids>,
+    /// The time it took to take the last sample, in microseconds
+    last_sample_duration: Arc<AtomicU64>,
+    /// Set to true to stop sampling
+    stop: watch::Sender<bool>,
+    /// The error which made sampling fail, if any
+    failure: Arc<Mutex<Option<SnapshotError>>>,
+    shutdown: Arc<Shutdown>,
 }
 
 impl Worker {
-    pub async fn new(update_interval: Duration, ctrl: DaemonControl) -> Result<Self, Never> {
+    /// Create the worker and spawn the sampling task.
+    ///
+    /// The sampling task runs at the fastest interval requested by connected clients, or at
+    /// [Settings::update_interval] if there are none. The first sample only serves as a reference
+    /// for the following ones, as it would yield averages since boot. When the task ends, either
+    /// because it was requested to stop or because there have been no clients for
+    /// [Settings::idle_timeout], daemon shutdown is requested through `ctrl`, unless the worker
+    /// runs outside of a daemon.
+    ///
+    /// A sample is skipped if taking a snapshot fails transiently, unless it happens more than
+    /// [MAX_SKIPPED_SAMPLES] times in a row. Then, or on any other failure, sampling fails, which
+    /// is reported to clients.
+    ///
+    /// The selectors requested by connected clients are resolved on every sample, and the command
+    /// line and executable of each process are only read while some of them need it.
+    ///
+    /// If [Settings::record] is set, each snapshot is recorded. Creating the worker fails if the
+    /// recording cannot be created, but failure to write to it later only stops recording.
+    pub async fn new(settings: Settings, ctrl: Option<DaemonControl>) -> Result<Self, String> {
+        let source = Procfs::live(settings.measure_pss);
+        Self::with_source(settings, ctrl, source).await
+    }
+
+    /// Create the worker like [Worker::new], but sampling snapshots taken from `source` instead of
+    /// the live system.
+    ///
+    /// The sampling task also ends when `source` runs out of snapshots.
+    pub async fn with_source(
+        settings: Settings,
+        ctrl: Option<DaemonControl>,
+        mut source: impl SnapshotSource,
+    ) -> Result<Self, String> {
         let (sender, _) = broadcast::channel(1);
-        let loads = sender.subscribe();
+        let ticks = sender.subscribe();
+        let recent = Arc::new(Mutex::new(VecDeque::new()));
+        let (stop, mut stopped) = watch::channel(false);
+        let (intervals, mut requested_intervals) = watch::channel(Intervals::new());
+        let (selectors, mut requested_selectors) = watch::channel(Selectors::new());
+        let (client_pids, connected_pids) = watch::channel(ClientPids::new());
+        let clients = Arc::new(AtomicU32::new(0));
+        let last_sample_duration = Arc::new(AtomicU64::new(0));
+        let shutdown = Arc::new(Shutdown::new(ctrl));
+        let failure = Arc::new(Mutex::new(None));
+        let Settings {
+            update_interval,
+            measure_pss,
+            idle_timeout,
+            ref record,
+        } = settings;
+        let mut recorder = record
+            .as_deref()
+            .map(|path| Recorder::create(path, &System::current(measure_pss)))
+            .transpose()?;
+        let sampler_clients = Arc::clone(&clients);
+        let sample_duration = Arc::clone(&last_sample_duration);
+        let sampler_shutdown = Arc::clone(&shutdown);
+        let sampler_recent = Arc::clone(&recent);
+        let sampler_failure = Arc::clone(&failure);
         tokio::spawn(async move {
-            let mut prev = None;
-            loop {
-                let next_sample_at = Instant::now() + update_interval;
-                let current_ticks = get_ticks_since_boot().expect("should know time in ticks");
-                let dt = current_ticks - prev.as_ref().map(|(t, _)| *t).unwrap_or(0u64);
-                let just_prev_loads = prev.take().map(|(_t, loads)| loads);
-                let (next, loads) = measure_pid_ticks(just_prev_loads);
-                let loads = loads
-                    .into_iter()
-                    .map(|(p, load)| (p, load as f32 / dt as f32))
-                    .collect();
-                let _ = sender.send(Arc::new(loads));
-                prev = Some((current_ticks, next));
-                sleep_until(next_sample_at).await;
+            let mut sampler = Sampler::new(procfs::ticks_per_second(), measure_pss);
+            let mut idle_since = None;
+            let mut skipped = 0;
+            let mut matchers = vec![];
+            'sampling: loop {
+                let sampling_started = Instant::now();
+                if requested_selectors.has_changed().unwrap_or(false) {
+                    // the selectors have been validated when requested
+                    matchers = requested_selectors
+                        .borrow_and_update()
+                        .keys()
+                        .filter_map(|selector| Matcher::new(selector).ok())
+                        .collect();
+                    source.read_commands(matchers.iter().any(Matcher::needs_commands));
+                }
+                let sampled = source.snapshot().map(|snapshot| {
+                    let snapshot = snapshot?;
+                    let tick = sampler.sample(&snapshot)?;
+                    if let Some(Err(e)) = recorder.as_mut().map(|r| r.record(&snapshot)) {
+                        warn!("error recording snapshot, recording stopped: {e}");
+                        recorder = None;
+                    }
+                    let clients: Vec<_> = connected_pids.borrow().keys().copied().collect();
+                    Ok(tick.map(|tick| Tick {
+                        selected: selector::select(&snapshot.processes, &matchers, &clients),
+                        ..tick
+                    }))
+                });
+                let tick = match sampled {
+                    Some(Ok(tick)) => {
+                        skipped = 0;
+                        tick
+                    }
+                    Some(Err(e @ SnapshotError::Transient(_))) if skipped < MAX_SKIPPED_SAMPLES => {
+                        warn!("skipping sample: {e}");
+                        skipped += 1;
+                        None
+                    }
+                    Some(Err(e)) => {
+                        error!("sampling failed: {e}");
+                        *sampler_failure.lock().unwrap() = Some(e);
+                        break;
+                    }
+                    None => {
+                        info!("no more snapshots, exiting");
+                        break;
+                    }
+                };
+                // After the first sample, which only serves as a reference, or a skipped one,
+                // sample again soon.
+                let hurry = tick.is_none();
+                if let Some(tick) = tick {
+                    let tick = Arc::new(tick);
+                    let longest = longest_interval(&requested_intervals.borrow(), update_interval);
+                    keep_recent(
+                        &mut sampler_recent.lock().unwrap(),
+                        Arc::clone(&tick),
+                        longest,
+                    );
+                    let _ = sender.send(tick);
+                }
+                let took = sampling_started.elapsed().as_micros() as u64;
+                sample_duration.store(took, Ordering::Relaxed);
+                if sampler_clients.load(Ordering::Relaxed) > 0 {
+                    idle_since = None;
+                } else if let Some(idle_timeout) = idle_timeout {
+                    let idle_since = *idle_since.get_or_insert(sampling_started);
+                    if idle_since.elapsed() >= idle_timeout {
+                        info!("no clients for {idle_timeout:?}, exiting");
+                        break;
+                    }
+                }
+                // Clients requesting a different interval may connect or disconnect while waiting.
+                loop {
+                    let mut interval = sampling_interval(
+                        &requested_intervals.borrow_and_update(),
+                        update_interval,
+                    );
+                    if hurry {
+                        interval = interval.min(WARMUP_INTERVAL);
+                    }
+                    select! {
+                        _ = sleep_until(sampling_started + interval) => break,
+                        _ = requested_intervals.changed() => continue,
+                        _ = stopped.wait_for(|stop| *stop) => break 'sampling,
+                    }
+                }
             }
+            sampler_shutdown.request().await;
         });
-        Ok(Self { loads, ctrl })
+        Ok(Self {
+            ticks,
+            recent,
+            settings,
+            started_at: Instant::now(),
+            clients,
+            intervals,
+            selectors,
+            client_pids,
+            last_sample_duration,
+            stop,
+            failure,
+            shutdown,
+        })
     }
 
     pub async fn handle_client(self: Arc<Self>, mut stream: TokioUnixStream) {
-        let mut loads = self.loads.resubscribe();
+        let pid = stream.peer_cred().ok().and_then(|cred| cred.pid());
         let (reader, writer) = stream.split();
-        let reader = BufReader::new(reader);
+        let mut reader = BufReader::new(reader);
         let mut writer = BufWriter::new(writer);
-        let pids: Vec<_> = unfold(reader, |mut reader| async {
-            reader.read_i32().await.ok().map(|pid| (pid, reader))
-        })
-        .collect()
-        .await;
+        let hello = match Hello::read_from(&mut reader).await {
+            Ok(hello) => hello,
+            Err(e) => {
+                warn!("error reading handshake, incompatible client? {e}");
+                return;
+            }
+        };
+        // Respond even if incompatible, so that the client can tell what the problem is.
+        if let Err(e) = Hello::new(self.capabilities()).write_to(&mut writer).await {
+            warn!("error writing handshake: {e}");
+            return;
+        }
+        if !hello.is_compatible() {
+            warn!(
+                "incompatible client protocol version {} (package version {})",
+                hello.version, hello.package_version
+            );
+            return;
+        }
+        let request = match Request::read_from(&mut reader).await {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("error reading request: {e}");
+                return;
+            }
+        };
+        let shutdown = match request {
+            Request::Monitor {
+                roots,
+                selectors,
+                interval,
+                wait_full,
+            } => {
+                self.monitor(&roots, &selectors, interval, wait_full, pid, &mut writer)
+                    .await
+            }
+            Request::Status => {
+                if let Err(e) = Response::Status(self.status()).write_to(&mut writer).await {
+                    warn!("error writing response: {e}");
+                }
+                false
+            }
+            Request::Shutdown => {
+                info!("shutdown requested by client");
+                // the sampler will request daemon shutdown when it stops
+                self.stop.send_replace(true);
+                if let Err(e) = Response::ShuttingDown.write_to(&mut writer).await {
+                    warn!("error writing response: {e}");
+                }
+                false
+            }
+        };
+        if let Err(e) = stream.shutdown().await {
+            warn!("error shutting down: {e}");
+        }
+        if shutdown {
+            self.shutdown.request().await;
+        }
+    }
+
+    /// Stream statistics of process trees rooted in `roots`, followed by those of the processes
+    /// selected by `selectors`, averaged over `interval`, until the client disconnects.
+    ///
+    /// A tree is reported as gone when its root process is not running, including when its PID is
+    /// used by a process which started at a different time than expected, and a selector when no
+    /// process matches it.
+    ///
+    /// The first statistics are averaged over the recent ticks that span up to `interval` and
+    /// were sampled with all of `selectors` requested. They are sent as soon as available, or if
+    /// `wait_full` is set, once they span `interval`.
+    ///
+    /// The client process `client`, if known, is never selected, see [selector::select].
+    ///
+    /// Returns true if sampling has ended, in which case the daemon should shut down.
+    async fn monitor(
+        &self,
+        roots: &[Root],
+        selectors: &[Selector],
+        interval: Duration,
+        wait_full: bool,
+        client: Option<i32>,
+        mut writer: impl AsyncWriteExt + Unpin,
+    ) -> bool {
+        if let Err(e) = selectors.iter().try_for_each(|s| Matcher::new(s).map(drop)) {
+            if let Err(e) = Response::Error(e).write_to(writer).await {
+                warn!("error writing response: {e}");
+            }
+            return false;
+        }
+        let interval = interval.max(MIN_INTERVAL);
+        let mut ticks = self.ticks.resubscribe();
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        self.intervals
+            .send_modify(|intervals| *intervals.entry(interval).or_default() += 1);
+        self.selectors.send_modify(|requested| {
+            for selector in selectors {
+                *requested.entry(selector.clone()).or_default() += 1;
+            }
+        });
+        if let Some(pid) = client {
+            self.client_pids
+                .send_modify(|pids| *pids.entry(pid).or_default() += 1);
+        }
+        let mut accumulated = Accumulated::new(roots.len() + selectors.len());
+        let add = |accumulated: &mut Accumulated, tick: &Tick| {
+            accumulated.add(tick.seconds, tick.trees(roots, selectors));
+        };
+        // Subscribed before looking at the recent ticks, so that none is missed in between.
+        let recent = self.recent.lock().unwrap().clone();
+        let mut skip = recent.len();
+        let mut seconds = 0.0;
+        while skip > 0 && seconds < interval.as_secs_f32() && recent[skip - 1].selects(selectors) {
+            skip -= 1;
+            seconds += recent[skip].seconds;
+        }
+        for tick in recent.iter().skip(skip) {
+            add(&mut accumulated, tick);
+        }
+        let mut last_seq = recent.back().map(|tick| tick.seq);
+        let mut send_recent = recent
+            .back()
+            .filter(|_| skip < recent.len())
+            .is_some_and(|last| !wait_full || accumulated.is_due(last.seconds, interval));
+        let mut sent_any = false;
         let worker_failed = 'serving: loop {
-            let pid_loads: Vec<_> = {
-                let loads = match loads.recv().await {
-                    Ok(loads) => loads,
+            if !send_recent {
+                let tick = match ticks.recv().await {
+                    Ok(tick) => tick,
                     Err(RecvError::Lagged(_)) => continue 'serving,
                     Err(RecvError::Closed) => break 'serving true,
                 };
-                pids.iter()
-                    .map(|pid| *loads.get(pid).unwrap_or(&f32::NAN))
-                    .collect()
-            };
-            for pid in pid_loads {
-                if let Err(e) = writer.write_f32(pid).await {
-                    warn!("error writing response: {e}");
-                    break 'serving false;
+                if last_seq.is_some_and(|seq| tick.seq <= seq) {
+                    continue 'serving;
+                }
+                last_seq = Some(tick.seq);
+                // sampled before the selectors were requested
+                if !tick.selects(selectors) {
+                    continue 'serving;
+                }
+                add(&mut accumulated, &tick);
+                if (sent_any || wait_full) && !accumulated.is_due(tick.seconds, interval) {
+                    continue 'serving;
                 }
             }
-            if let Err(e) = writer.flush().await {
-                warn!("error flushing stream: {e}");
+            send_recent = false;
+            sent_any = true;
+            if let Err(e) = Response::Update(accumulated.take())
+                .write_to(&mut writer)
+                .await
+            {
+                warn!("error writing response: {e}");
                 break 'serving false;
             }
         };
-        if let Err(e) = stream.shutdown().await {
-            warn!("error shutting down: {e}");
+        self.intervals.send_modify(|intervals| {
+            let count = intervals
+                .get_mut(&interval)
+                .expect("interval must be registered");
+            *count -= 1;
+            if *count == 0 {
+                intervals.remove(&interval);
+            }
+        });
+        self.selectors.send_modify(|requested| {
+            for selector in selectors {
+                let count = requested
+                    .get_mut(selector)
+                    .expect("selector must be registered");
+                *count -= 1;
+                if *count == 0 {
+                    requested.remove(selector);
+                }
+            }
+        });
+        if let Some(pid) = client {
+            self.client_pids.send_modify(|pids| {
+                let count = pids.get_mut(&pid).expect("client must be registered");
+                *count -= 1;
+                if *count == 0 {
+                    pids.remove(&pid);
+                }
+            });
         }
+        self.clients.fetch_sub(1, Ordering::Relaxed);
         if worker_failed {
-            self.ctrl.shutdown().await;
+            let message = match &*self.failure.lock().unwrap() {
+                _ if *self.stop.borrow() => "daemon is shutting down".to_owned(),
+                Some(e) => format!("sampling process trees failed: {e}"),
+                None => "sampling process trees failed".to_owned(),
+            };
+            if let Err(e) = Response::Error(message).write_to(writer).await {
+                warn!("error writing response: {e}");
+            }
+        }
+        worker_failed
+    }
+
+    fn status(&self) -> DaemonStatus {
+        DaemonStatus {
+            uptime: self.started_at.elapsed(),
+            clients: self.clients.load(Ordering::Relaxed),
+            interval: sampling_interval(&self.intervals.borrow(), self.settings.update_interval),
+            last_sample_duration: Duration::from_micros(
+                self.last_sample_duration.load(Ordering::Relaxed),
+            ),
+        }
+    }
+
+    /// The optional features supported by this worker.
+    fn capabilities(&self) -> Vec<String> {
+        let mut capabilities = vec![];
+        if self.settings.measure_pss {
+            capabilities.push(CAPABILITY_PSS.to_owned());
         }
+        capabilities
     }
 }
 
-/// Perform one measurement of CPU loads for each process tree.
+/// Compute the statistics a client monitoring `roots` and `selectors` at `interval` would be sent
+/// if the snapshots of `recording` were being captured live.
+///
+/// Returns the UNIX timestamp of the last snapshot contributing to each update, and the statistics
+/// of each process tree. Unless `wait_full` is set, the first update is that of the first tick.
+pub fn replay(
+    recording: &Recording,
+    roots: &[Root],
+    selectors: &[Selector],
+    interval: Duration,
+    wait_full: bool,
+) -> Result<Vec<(f64, Vec<TreeState>)>, String> {
+    let matchers = selectors
+        .iter()
+        .map(Matcher::new)
+        .collect::<Result<Vec<_>, _>>()?;
+    let interval = interval.max(MIN_INTERVAL);
+    let System {
+        ticks_per_second,
+        measure_pss,
+        ..
+    } = recording.system;
+    let mut sampler = Sampler::new(ticks_per_second, measure_pss);
+    let mut accumulated = Accumulated::new(roots.len() + selectors.len());
+    let mut updates = vec![];
+    for snapshot in &recording.snapshots {
+        let Some(tick) = sampler.sample(snapshot).map_err(|e| e.to_string())? else {
+            continue;
+        };
+        let selected = selector::select(&snapshot.processes, &matchers, &[]);
+        let tick = Tick { selected, ..tick };
+        accumulated.add(tick.seconds, tick.trees(roots, selectors));
+        if (updates.is_empty() && !wait_full) || accumulated.is_due(tick.seconds, interval) {
+            updates.push((snapshot.time, accumulated.take()));
+        }
+    }
+    Ok(updates)
+}
+
+/// The interval at which to sample, which is the fastest of the intervals requested by clients, or
+/// `default` if there are no clients.
+fn sampling_interval(requested: &Intervals, default: Duration) -> Duration {
+    requested
+        .first_key_value()
+        .map(|(interval, _)| *interval)
+        .unwrap_or(default)
+        .max(MIN_INTERVAL)
+}
+
+/// The longest interval requested by clients, or `default` if there are no clients.
+fn longest_interval(requested: &Intervals, default: Duration) -> Duration {
+    requested
+        .last_key_value()
+        .map(|(interval, _)| *interval)
+        .unwrap_or(default)
+}
+
+/// Append `tick` to `recent` and drop the oldest ticks not needed to span `span`.
+fn keep_recent(recent: &mut VecDeque<Arc<Tick>>, tick: Arc<Tick>, span: Duration) {
+    recent.push_back(tick);
+    let mut seconds: f32 = recent.iter().map(|tick| tick.seconds).sum();
+    while let Some(oldest) = recent.front() {
+        if seconds - oldest.seconds < span.as_secs_f32() {
+            break;
+        }
+        seconds -= oldest.seconds;
+        recent.pop_front();
+    }
+}
+
+/// Statistics of process trees accumulated over consecutive ticks.
+///
+/// Loads and I/O rates are averaged over the accumulated time the tree was alive, while the other
+/// statistics and whether a tree is gone are taken from the latest tick.
+struct Accumulated {
+    /// The accumulated time in seconds
+    seconds: f32,
+    /// The sums of loads and I/O rates weighted by time, and the latest values of other statistics
+    stats: Vec<TreeStats>,
+    /// The accumulated time in seconds each tree was alive for
+    alive_seconds: Vec<f32>,
+    /// Whether each tree is gone
+    gone: Vec<bool>,
+}
+
+impl Tick {
+    /// The state of each process tree rooted in `roots`, followed by that of the processes
+    /// selected by each of `selectors`, see [Worker::monitor].
+    fn trees<'a>(
+        &'a self,
+        roots: &'a [Root],
+        selectors: &'a [Selector],
+    ) -> impl Iterator<Item = TreeState> + 'a {
+        let roots = roots.iter().map(|root| match self.stats.get(&root.pid) {
+            Some((start_time, stats))
+                if root
+                    .start_time
+                    .is_none_or(|expected| expected == *start_time) =>
+            {
+                TreeState::Alive(*stats)
+            }
+            _ => TreeState::Gone,
+        });
+        let selected = selectors.iter().map(|selector| {
+            self.selected
+                .get(selector)
+                .into_iter()
+                .flatten()
+                .filter_map(|pid| self.stats.get(pid))
+                .map(|(_, stats)| *stats)
+                .reduce(add_stats)
+                .map_or(TreeState::Gone, TreeState::Alive)
+        });
+        roots.chain(selected)
+    }
+
+    /// Check if the processes selected by all of `selectors` are known.
+    fn selects(&self, selectors: &[Selector]) -> bool {
+        selectors.iter().all(|s| self.selected.contains_key(s))
+    }
+}
+
+/// The statistics of two process trees taken together.
+fn add_stats(a: TreeStats, b: TreeStats) -> TreeStats {
+    TreeStats {
+        load: a.load + b.load,
+        lifetime: a.lifetime + b.lifetime,
+        rss: a.rss + b.rss,
+        pss: a.pss + b.pss,
+        io_read: a.io_read + b.io_read,
+        io_write: a.io_write + b.io_write,
+        procs: a.procs + b.procs,
+        threads: a.threads + b.threads,
+        hidden: a.hidden + b.hidden,
+    }
+}
+
+/// Computes the statistics of process trees from consecutive snapshots.
+struct Sampler {
+    ticks_per_second: u64,
+    measure_pss: bool,
+    /// The consecutive number of the next tick
+    seq: u64,
+    /// The time of the previous snapshot in ticks and the sample computed from it
+    prev: Option<(u64, Sample)>,
+}
+
+impl Sampler {
+    fn new(ticks_per_second: u64, measure_pss: bool) -> Self {
+        Self {
+            ticks_per_second,
+            measure_pss,
+            seq: 0,
+            prev: None,
+        }
+    }
+
+    /// Compute the statistics of all process trees since the previous snapshot.
+    ///
+    /// Returns `None` for the first snapshot, which only serves as a reference for the following
+    /// ones, as it would yield averages since boot, and an error if the snapshot is not later than
+    /// the previous one.
+    fn sample(&mut self, snapshot: &Snapshot) -> Result<Option<Tick>, SnapshotError> {
+        let prev_uptime = self.prev.as_ref().map(|(t, _)| *t).unwrap_or(0u64);
+        let dt = snapshot
+            .uptime
+            .checked_sub(prev_uptime)
+            .filter(|dt| *dt > 0)
+            .ok_or_else(|| {
+                SnapshotError::Transient(format!(
+                    "uptime went from {prev_uptime} to {} ticks",
+                    snapshot.uptime
+                ))
+            })?;
+        let prev = self.prev.as_ref().map(|(_t, sample)| sample);
+        let (next, loads) = measure_pid_ticks(prev, &snapshot.processes);
+        let memory = measure_pid_memory(&next);
+        let io = measure_pid_io(prev, &next);
+        let counts = measure_pid_counts(&next);
+        let lifetime = measure_pid_lifetime(&next, snapshot.uptime);
+        let seconds = dt as f32 / self.ticks_per_second as f32;
+        let stats = loads
+            .into_iter()
+            .map(|(p, load)| {
+                let (rss, pss) = *memory.get(&p).expect("memory must contain pid");
+                let (read, written) = *io.get(&p).expect("io must contain pid");
+                let (procs, threads, hidden) = *counts.get(&p).expect("counts must contain pid");
+                let start_time = next
+                    .pids
+                    .get(&p)
+                    .expect("sample must contain pid")
+                    .start_time;
+                let stats = TreeStats {
+                    load: load as f32 / dt as f32,
+                    lifetime: *lifetime.get(&p).expect("lifetime must contain pid"),
+                    rss: rss as f32,
+                    pss: if self.measure_pss {
+                        pss as f32
+                    } else {
+                        f32::NAN
+                    },
+                    io_read: read as f32 / seconds,
+                    io_write: written as f32 / seconds,
+                    procs: procs as f32,
+                    threads: threads as f32,
+                    hidden: hidden as f32,
+                };
+                (p, (start_time, stats))
+            })
+            .collect();
+        let first = self.prev.is_none();
+        self.prev = Some((snapshot.uptime, next));
+        if first {
+            return Ok(None);
+        }
+        let tick = Tick {
+            seq: self.seq,
+            seconds,
+            stats,
+            selected: HashMap::new(),
+        };
+        self.seq += 1;
+        Ok(Some(tick))
+    }
+}
+
+impl Accumulated {
+    const EMPTY: TreeStats = TreeStats {
+        load: 0.0,
+        io_read: 0.0,
+        io_write: 0.0,
+        ..TreeStats::MISSING
+    };
+
+    fn new(len: usize) -> Self {
+        Self {
+            seconds: 0.0,
+            stats: vec![Self::EMPTY; len],
+            alive_seconds: vec![0.0; len],
+            gone: vec![false; len],
+        }
+    }
+
+    /// Check if the statistics should be sent after adding a tick of `last_seconds`, which is as
+    /// soon as waiting for another such tick would overshoot `interval` more than sending now
+    /// undershoots it.
+    fn is_due(&self, last_seconds: f32, interval: Duration) -> bool {
+        self.seconds + last_seconds / 2.0 >= interval.as_secs_f32()
+    }
+
+    fn add(&mut self, seconds: f32, trees: impl Iterator<Item = TreeState>) {
+        let accumulated = self
+            .stats
+            .iter_mut()
+            .zip(&mut self.alive_seconds)
+            .zip(&mut self.gone);
+        for (((acc, alive_seconds), gone), tree) in accumulated.zip(trees) {
+            let TreeState::Alive(cur) = tree else {
+                *gone = true;
+                continue;
+            };
+            *gone = false;
+            *alive_seconds += seconds;
+            *acc = TreeStats {
+                load: acc.load + cur.load * seconds,
+                io_read: acc.io_read + cur.io_read * seconds,
+                io_write: acc.io_write + cur.io_write * seconds,
+                ..cur
+            };
+        }
+        self.seconds += seconds;
+    }
+
+    /// Return the statistics averaged over the accumulated time and start accumulating anew.
+    fn take(&mut self) -> Vec<TreeState> {
+        self.seconds = 0.0;
+        self.stats
+            .iter_mut()
+            .zip(&mut self.alive_seconds)
+            .zip(&self.gone)
+            .map(|((acc, alive_seconds), gone)| {
+                let seconds = std::mem::take(alive_seconds);
+                let stats = TreeStats {
+                    load: acc.load / seconds,
+                    io_read: acc.io_read / seconds,
+                    io_write: acc.io_write / seconds,
+                    ..*acc
+                };
+                *acc = Self::EMPTY;
+                match gone {
+                    true => TreeState::Gone,
+                    false => TreeState::Alive(stats),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A handle to request daemon shutdown at most once.
+///
+/// Daemon shutdown may be requested by the sampler and by all client handlers at once, but the
+/// daemon only receives the first request, so any further ones would never complete.
+struct Shutdown {
+    ctrl: Option<DaemonControl>,
+    requested: AtomicBool,
+}
+
+impl Shutdown {
+    fn new(ctrl: Option<DaemonControl>) -> Self {
+        Self {
+            ctrl,
+            requested: AtomicBool::new(false),
+        }
+    }
+
+    async fn request(&self) {
+        let Some(ctrl) = &self.ctrl else {
+            return;
+        };
+        if !self.requested.swap(true, Ordering::Relaxed) {
+            ctrl.shutdown().await;
+        }
+    }
+}
+
+/// Perform one measurement of CPU loads for each process tree from the information about all
+/// processes at a single point in time.
 ///
 /// Returns a pair consisting of:
 /// - the measured sample, which must be passed to another call to [`measure_pid_ticks`] in order
@@ -102,130 +845,272 @@ impl Worker {
 ///
 /// Passing `None` as `prev` allows to measure the average CPU/core load of a process tree since
 /// boot, if the number of ticks is divided by the number of ticks since boot.
-fn measure_pid_ticks(prev: Option<Sample>) -> (Sample, HashMap<i32, i64>) {
+fn measure_pid_ticks(
+    prev: Option<&Sample>,
+    processes: &[ProcessInfo],
+) -> (Sample, HashMap<i32, i64>) {
     // The following words always refer to the following specific concepts:
-    // total - total number of ticks used by some process or multiple processes since creation,
+    // total - the value of a counter of a process since its creation,
+    // waited - the part of a total contributed by descendants that have already been reaped,
     // cumulated - the sum of values of a certain property over a process and all its descendants,
-    // recent - one that happened before the last measurement and the current measurement.
+    // delta - the increase of a counter since the previous measurement.
 
+    let visible: HashSet<_> = processes.iter().map(|info| info.pid).collect();
+    // A process whose parent is not visible, e.g. init with `hidepid`, is the root of a tree.
+    let mut parents: HashMap<_, _> = processes
+        .iter()
+        .filter(|info| visible.contains(&info.ppid))
+        .map(|info| (info.pid, info.ppid))
+        .collect();
+    // Processes are not read all at once, so a PID reused in the meantime may make a process its
+    // own ancestor. Such a cycle is broken by making one of the processes in it a root.
+    while let Some(pid) = find_parent_cycle(&parents) {
+        parents.remove(&pid);
+    }
     let mut children: HashMap<_, Vec<_>> = HashMap::new();
-    let all_procs = procfs::process::all_processes().expect("can't read /proc");
-    let samples = all_procs.filter_map(|prc| {
-        let stat = prc.and_then(|prc| prc.stat()).ok()?;
+    let samples = processes.iter().map(|info| {
         let sample = PidSample {
-            // total time in ticks spent by process in user and kernel since creation
-            total_self_ticks: stat.utime + stat.stime,
-            // total time in ticks spent by process's children (direct descendants only), that does
-            // not include the ones that are still alive (and is not cumulated just yet!)
-            cumulated_total_subtree_ticks: stat.cutime + stat.cstime,
+            total_self_ticks: info.self_ticks,
+            total_waited_ticks: info.children_ticks,
+            rss: info.rss,
+            pss: info.pss,
+            total_read_bytes: info.read_bytes,
+            total_write_bytes: info.write_bytes,
+            threads: info.threads,
+            hidden_children: info.hidden_children,
+            start_time: info.start_time,
         };
-        if stat.ppid != 0 {
-            children.entry(stat.ppid).or_default().push(stat.pid);
+        if let Some(ppid) = parents.get(&info.pid) {
+            children.entry(*ppid).or_default().push(info.pid);
         }
-        children.entry(stat.pid).or_default();
-        Some((stat.pid, sample))
+        children.entry(info.pid).or_default();
+        (info.pid, sample)
     });
-    let mut samples: HashMap<_, _> = samples.collect();
-    let actually_cumulated_total_subtree_ticks = get_cumulated(&children, |id| {
-        samples
-            .get(&id)
-            .expect("samples must contain pid")
-            .cumulated_total_subtree_ticks
-    });
-    for (k, v) in &mut samples {
-        // Now, cumulated is actually cumulated; still, this only includes the ticks spent by
-        // processes that have already died.
-        v.cumulated_total_subtree_ticks = *actually_cumulated_total_subtree_ticks
-            .get(k)
-            .expect("actually cumulated must contain pid");
-    }
     let cur = Sample {
-        pids: samples,
+        pids: samples.collect(),
         children,
     };
+    // The ticks spent by a process and its waited-for descendants only ever grow, and those of a
+    // reaped process are added to its parent's, just like I/O counters.
+    let ticks = get_cumulated_counter_delta(
+        prev,
+        &cur,
+        |s| s.total_self_ticks + s.total_waited_ticks,
+        |s| s.total_waited_ticks,
+    );
+    (cur, ticks)
+}
 
-    // These are the ticks used by each process (without any descendants included), i.e. the number
-    // of ticks spent since the last measurement
-    // For the first measurement, it's the number of ticks spent since boot.
-    let self_ticks_since_prev: HashMap<_, _> = cur
-        .pids
-        .iter()
-        .map(|(pid, sample)| {
-            let prev_sample = prev.as_ref().and_then(|prev| prev.pids.get(pid));
-            let self_ticks_since_prev =
-                sample.total_self_ticks - prev_sample.map(|p| p.total_self_ticks).unwrap_or(0);
-            (*pid, self_ticks_since_prev)
+/// Calculate the memory used by each process tree in a sample.
+///
+/// Returns a `PID -> (rss, pss)` mapping, where both values are the sums of the respective memory
+/// sizes (in bytes) of all processes in a process tree rooted in `PID`.
+fn measure_pid_memory(sample: &Sample) -> HashMap<i32, (u64, u64)> {
+    let rss = get_cumulated(&sample.children, |id| {
+        sample.pids.get(&id).expect("sample must contain pid").rss
+    });
+    let pss = get_cumulated(&sample.children, |id| {
+        sample.pids.get(&id).expect("sample must contain pid").pss
+    });
+    rss.into_iter()
+        .map(|(pid, rss)| (pid, (rss, *pss.get(&pid).expect("pss must contain pid"))))
+        .collect()
+}
+
+/// Calculate the average load of each process tree since its root process started.
+///
+/// Returns a `PID -> load` mapping, where the load is the total number of ticks used by all
+/// processes in a process tree rooted in `PID` (including its already waited-for descendants),
+/// divided by the number of ticks since `PID` started, given that it is `uptime` ticks since boot.
+fn measure_pid_lifetime(sample: &Sample, uptime: u64) -> HashMap<i32, f32> {
+    let ticks = get_cumulated(&sample.children, |id| {
+        let sample = sample.pids.get(&id).expect("sample must contain pid");
+        sample.total_self_ticks + sample.total_waited_ticks
+    });
+    ticks
+        .into_iter()
+        .map(|(pid, ticks)| {
+            let sample = sample.pids.get(&pid).expect("sample must contain pid");
+            let elapsed = uptime.saturating_sub(sample.start_time).max(1);
+            (pid, ticks as f32 / elapsed as f32)
         })
-        .collect();
+        .collect()
+}
 
-    let almost_ticks = get_cumulated(&cur.children, |id| {
-        *self_ticks_since_prev
+/// Count processes and threads in each process tree in a sample.
+///
+/// Returns a `PID -> (processes, threads, hidden)` mapping, where the values are the numbers of
+/// processes and threads in a process tree rooted in `PID`, including `PID` itself, and the number
+/// of hidden children of the processes in the tree.
+fn measure_pid_counts(sample: &Sample) -> HashMap<i32, (u64, u64, u64)> {
+    let procs = get_cumulated(&sample.children, |_| 1);
+    let threads = get_cumulated(&sample.children, |id| {
+        sample
+            .pids
             .get(&id)
-            .expect("itermediate shouldn't miss any value")
+            .expect("sample must contain pid")
+            .threads
     });
-
-    let empty = HashMap::new();
-    let prev_children = prev.as_ref().map(|prev| &prev.children).unwrap_or(&empty);
-    // The total ticks spent by processes that existed in previous sample and are dead now, but
-    // measured only until the previous sample, i.e. excluding any ticks they have spent between
-    // the last measurement and the time they died. Cumulated over whole subtrees.
-    let prev_cumulated_total_ticks_killed_recently = get_cumulated(prev_children, |id| {
-        if cur.pids.contains_key(&id) {
-            // we don't care about tasks alive now
-            return 0;
-        }
-        // we'll need to subtract total ticks until previous sample
-        prev.as_ref()
-            .expect("prev must be some at this point") // otherwise, prev_children would be empty
+    let hidden = get_cumulated(&sample.children, |id| {
+        sample
             .pids
             .get(&id)
-            .expect("prev must contain pid") // because id is from prev_children
-            .total_self_ticks
+            .expect("sample must contain pid")
+            .hidden_children
     });
+    procs
+        .into_iter()
+        .map(|(pid, procs)| {
+            let threads = *threads.get(&pid).expect("threads must contain pid");
+            let hidden = *hidden.get(&pid).expect("hidden must contain pid");
+            (pid, (procs, threads, hidden))
+        })
+        .collect()
+}
 
-    // Now, almost_ticks contains ticks spent by all descendants still alive. But there are also
-    // descendants already dead now that have contributed to the total ticks:
-    // 1. those that were spawned and then died between the last sample and now,
-    // 2. those that were spawned before the last sample and died between the last sample and now -
-    //    their ticks were taken into account in the previous measurement, but the ticks they spent
-    //    between then and now have not been accounted for.
-    let final_ticks = almost_ticks.into_iter().map(|(pid, self_ticks)| {
-        let cur_total_subtree_ticks = cur
+/// Calculate the I/O performed by each process tree since the previous sample.
+///
+/// Returns a `PID -> (read, written)` mapping, where both values are the numbers of bytes read
+/// from and written to storage by all processes in a process tree rooted in `PID` since `prev` was
+/// captured (or since boot, if `prev` is `None`).
+fn measure_pid_io(prev: Option<&Sample>, cur: &Sample) -> HashMap<i32, (i64, i64)> {
+    // the bytes of a process and those of its waited-for descendants cannot be told apart
+    let read =
+        get_cumulated_counter_delta(prev, cur, |s| s.total_read_bytes, |s| s.total_read_bytes);
+    let written =
+        get_cumulated_counter_delta(prev, cur, |s| s.total_write_bytes, |s| s.total_write_bytes);
+    read.into_iter()
+        .map(|(pid, read)| {
+            let written = *written.get(&pid).expect("written must contain pid");
+            (pid, (read, written))
+        })
+        .collect()
+}
+
+/// Calculate the increase of a per-process counter since the previous sample over process trees.
+///
+/// The counter of each process must already include the values of its waited-for descendants, as
+/// is the case with I/O statistics and CPU time including that of waited-for children, i.e. when a
+/// process is reaped, its counter gets added to its parent's counter. `waited` is the part of the
+/// counter added that way, or the whole counter if the parts cannot be told apart.
+fn get_cumulated_counter_delta<F, W>(
+    prev: Option<&Sample>,
+    cur: &Sample,
+    counter: F,
+    waited: W,
+) -> HashMap<i32, i64>
+where
+    F: Fn(&PidSample) -> u64,
+    W: Fn(&PidSample) -> u64,
+{
+    // The increase of the counter of each process alive now, including the whole counters of
+    // descendants that were reaped since the last measurement.
+    let mut delta: HashMap<_, _> = cur
+        .pids
+        .iter()
+        .map(|(pid, sample)| {
+            let prev_value = prev
+                .and_then(|prev| prev.same_process(*pid, cur))
+                .map(&counter)
+                .unwrap_or(0);
+            (*pid, counter(sample) as i64 - prev_value as i64)
+        })
+        .collect();
+    if let Some(prev) = prev {
+        // Descendants that existed in the previous sample and died since then have been added to
+        // their reapers' counters with their whole values, but the part until the previous sample
+        // has already been accounted for. A descendant may have been reparented before it died,
+        // so its reaper is looked for among its ancestors at last sight, starting from the
+        // closest one still alive. Each of them can only have reaped as much as its waited part
+        // grew by, and the rest was reaped further up, e.g. by init.
+        let parents: HashMap<_, _> = prev
+            .children
+            .iter()
+            .flat_map(|(parent, children)| children.iter().map(move |child| (*child, *parent)))
+            .collect();
+        let mut unclaimed: HashMap<_, _> = prev
             .pids
-            .get(&pid)
-            .expect("cur shouldn't miss any values")
-            .cumulated_total_subtree_ticks;
-        let prev_total_subtree_ticks = prev
-            .as_ref()
-            .and_then(|prev| prev.pids.get(&pid))
-            .map(|s| s.cumulated_total_subtree_ticks)
-            .unwrap_or(0);
-        // If we subtract the ticks spent by all descendants killed before previous measurement from
-        // the time spent by all descendats killed before current measurement, we get the ticks
-        // spent by all descendants that were killed exactly between the last measurement and the
-        // current one. That is almost what we want, with one exception.
-        // 1. spawned recently and already dead - ok,
-        // 2. spawned earlier and already dead - they contribute the total ticks, even those spent
-        //    before the previous measurement - not ok.
-        let ticks_of_recently_killed = cur_total_subtree_ticks - prev_total_subtree_ticks;
-        let until_prev = *prev_cumulated_total_ticks_killed_recently
-            .get(&pid)
-            .unwrap_or(&0);
-        // And that's our offset described above the loop.
-        let offset = ticks_of_recently_killed - until_prev as i64;
-        (pid, self_ticks as i64 + offset)
-    });
-    let final_ticks = final_ticks.collect();
-    (cur, final_ticks)
+            .iter()
+            .filter_map(|(pid, before)| {
+                let now = cur.same_process(*pid, prev)?;
+                Some((*pid, waited(now).saturating_sub(waited(before))))
+            })
+            .collect();
+        // Closer descendants first, as the children of a living process must have been reaped by
+        // it, while more distant ones may have been reparented.
+        let mut dead: Vec<_> = prev
+            .pids
+            .iter()
+            .filter(|(pid, _)| cur.same_process(**pid, prev).is_none())
+            .map(|(pid, sample)| {
+                let mut distance = 0;
+                let mut ancestor = parents.get(pid);
+                while let Some(id) = ancestor.filter(|id| !unclaimed.contains_key(id)) {
+                    distance += 1;
+                    ancestor = parents.get(id);
+                }
+                (distance, *pid, counter(sample), ancestor.copied())
+            })
+            .collect();
+        dead.sort();
+        for (_, _, mut value, mut ancestor) in dead {
+            while value > 0 {
+                let Some(id) = ancestor else {
+                    break;
+                };
+                if let Some(unclaimed) = unclaimed.get_mut(&id) {
+                    let claimed = value.min(*unclaimed);
+                    *unclaimed -= claimed;
+                    value -= claimed;
+                    *delta.get_mut(&id).expect("delta must contain pid") -= claimed as i64;
+                }
+                ancestor = parents.get(&id).copied();
+            }
+        }
+    }
+    get_cumulated(&cur.children, |id| {
+        *delta.get(&id).expect("delta must contain pid")
+    })
+}
+
+/// Find a process that is its own ancestor according to the `PID -> parent PID` mapping `parents`.
+fn find_parent_cycle(parents: &HashMap<i32, i32>) -> Option<i32> {
+    let mut acyclic = HashSet::new();
+    for &start in parents.keys() {
+        let mut path = HashSet::new();
+        let mut pid = start;
+        while let Some(&ppid) = parents.get(&pid) {
+            if acyclic.contains(&pid) {
+                break;
+            }
+            if !path.insert(pid) {
+                return Some(pid);
+            }
+            pid = ppid;
+        }
+        acyclic.extend(path);
+    }
+    None
 }
 
 struct Sample {
     /// A sample for every discovered process
     pids: HashMap<i32, PidSample>,
-    /// The process tree based on the parent-child relationship in form of adjacency lists
+    /// The process tree based on the parent-child relationship in form of adjacency lists, with a
+    /// list for every process in `pids` and no cycles
     children: HashMap<i32, Vec<i32>>,
 }
 
+impl Sample {
+    /// Find the sample of the process with `pid`, provided that it is the same process as the one
+    /// with `pid` in `other`, and not another one reusing the PID.
+    fn same_process(&self, pid: i32, other: &Sample) -> Option<&PidSample> {
+        let sample = self.pids.get(&pid)?;
+        let other = other.pids.get(&pid)?;
+        (sample.start_time == other.start_time).then_some(sample)
+    }
+}
+
 struct PidSample {
     /// The total time in ticks consumed by the process since its creation.
     total_self_ticks: u64,
@@ -233,21 +1118,23 @@ struct PidSample {
     /// children).
     ///
     /// This only includes processes that are alredy dead at the time the sample is acquired.
-    cumulated_total_subtree_ticks: i64,
-}
-
-fn get_ticks_since_boot() -> Result<u64, ()> {
-    let mut t = libc::tms {
-        tms_utime: 0,
-        tms_stime: 0,
-        tms_cutime: 0,
-        tms_cstime: 0,
-    };
-    let ticks = unsafe { libc::times(&mut t) };
-    if ticks < 0 {
-        Err(())?
-    }
-    Ok(ticks as u64)
+    total_waited_ticks: u64,
+    /// The resident set size of the process in bytes.
+    rss: u64,
+    /// The proportional set size of the process in bytes, or zero if not measured.
+    pss: u64,
+    /// The total number of bytes read from storage by the process and all its waited-for
+    /// descendants.
+    total_read_bytes: u64,
+    /// The total number of bytes written to storage by the process and all its waited-for
+    /// descendants.
+    total_write_bytes: u64,
+    /// The number of threads of the process.
+    threads: u64,
+    /// The number of children of the process hidden by `hidepid`.
+    hidden_children: u64,
+    /// The time the process started, in ticks since boot.
+    start_time: u64,
 }
 
 fn get_cumulated<Id, V, F>(children: &HashMap<Id, Vec<Id>>, value: F) -> HashMap<Id, V>
@@ -288,3 +1175,931 @@ where
     cumulated.insert(root, total);
     total
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::fixture;
+
+    fn stats(load: f32, rss: f32) -> TreeStats {
+        TreeStats {
+            load,
+            lifetime: 0.5,
+            rss,
+            pss: f32::NAN,
+            io_read: load * 1000.0,
+            io_write: 0.0,
+            procs: 1.0,
+            threads: 1.0,
+            hidden: 0.0,
+        }
+    }
+
+    #[test]
+    fn averages_accumulated_ticks() {
+        let alive = |load, rss| TreeState::Alive(stats(load, rss));
+        let mut accumulated = Accumulated::new(3);
+        accumulated.add(
+            1.0,
+            [alive(1.0, 10.0), TreeState::Gone, alive(1.0, 10.0)].into_iter(),
+        );
+        accumulated.add(
+            3.0,
+            [alive(0.0, 20.0), alive(1.0, 10.0), TreeState::Gone].into_iter(),
+        );
+        assert_eq!(accumulated.seconds, 4.0);
+        let averaged = accumulated.take();
+        let TreeState::Alive(first) = averaged[0] else {
+            panic!("first tree should be alive");
+        };
+        assert_eq!(first.load, 0.25);
+        assert_eq!(first.io_read, 250.0);
+        assert_eq!(first.rss, 20.0);
+        // gone in some of the ticks, averaged over the others
+        let TreeState::Alive(second) = averaged[1] else {
+            panic!("second tree should be alive");
+        };
+        assert_eq!(second.load, 1.0);
+        assert_eq!(second.io_read, 1000.0);
+        assert_eq!(second.rss, 10.0);
+        // gone in the latest tick
+        assert_eq!(averaged[2], TreeState::Gone);
+        // starts anew
+        accumulated.add(
+            2.0,
+            [alive(0.5, 10.0), alive(1.0, 10.0), alive(1.0, 10.0)].into_iter(),
+        );
+        assert_eq!(accumulated.take()[1].stats().load, 1.0);
+    }
+
+    fn pid_sample(start_time: u64, total_read_bytes: u64) -> PidSample {
+        PidSample {
+            total_self_ticks: 0,
+            total_waited_ticks: 0,
+            rss: 0,
+            pss: 0,
+            total_read_bytes,
+            total_write_bytes: 0,
+            threads: 1,
+            hidden_children: 0,
+            start_time,
+        }
+    }
+
+    #[test]
+    fn ignores_reused_pids() {
+        let prev = Sample {
+            pids: HashMap::from([(1, pid_sample(0, 100)), (2, pid_sample(10, 1000))]),
+            children: HashMap::from([(1, vec![2]), (2, vec![])]),
+        };
+        // 2 was reaped by 1 and its PID was reused by a new child of 1
+        let cur = Sample {
+            pids: HashMap::from([(1, pid_sample(0, 1150)), (2, pid_sample(20, 30))]),
+            children: HashMap::from([(1, vec![2]), (2, vec![])]),
+        };
+        let read = |s: &PidSample| s.total_read_bytes;
+        let read = get_cumulated_counter_delta(Some(&prev), &cur, read, read);
+        assert_eq!(read.get(&2), Some(&30));
+        // 50 read by 1 itself and 30 by the new 2, the 1000 read by the old 2 were already counted
+        assert_eq!(read.get(&1), Some(&80));
+    }
+
+    #[test]
+    fn keeps_recent_ticks() {
+        let tick = |seq| {
+            Arc::new(Tick {
+                seq,
+                seconds: 0.5,
+                stats: HashMap::new(),
+                selected: HashMap::new(),
+            })
+        };
+        let mut recent = VecDeque::new();
+        for seq in 0..10 {
+            keep_recent(&mut recent, tick(seq), Duration::from_secs(2));
+        }
+        let kept: Vec<_> = recent.iter().map(|tick| tick.seq).collect();
+        assert_eq!(kept, vec![6, 7, 8, 9]);
+        keep_recent(&mut recent, tick(10), Duration::from_millis(100));
+        assert_eq!(recent.len(), 1);
+    }
+
+    #[test]
+    fn replays_recording() {
+        let process = |pid, ppid, self_ticks, children_ticks| ProcessInfo {
+            self_ticks,
+            children_ticks,
+            ..fixture::process(pid, ppid, if pid == 1 { "init" } else { "make" })
+        };
+        let snapshot = |time, uptime, processes| Snapshot {
+            time,
+            uptime,
+            processes,
+        };
+        let recording = Recording {
+            system: System {
+                ticks_per_second: 100,
+                cores: 1.0,
+                memory: 1.0,
+                measure_pss: false,
+            },
+            snapshots: vec![
+                snapshot(0.0, 1000, vec![process(1, 0, 0, 0), process(2, 1, 0, 0)]),
+                snapshot(1.0, 1100, vec![process(1, 0, 10, 0), process(2, 1, 40, 0)]),
+                // 2 spent another 20 ticks, exited and was reaped by 1
+                snapshot(2.0, 1200, vec![process(1, 0, 20, 60)]),
+            ],
+        };
+        let roots = [1, 2].map(|pid| Root {
+            pid,
+            start_time: None,
+        });
+        let selectors = [Selector::Comm("make".to_owned())];
+        let loads = |interval| -> Vec<_> {
+            replay(&recording, &roots, &selectors, interval, false)
+                .unwrap()
+                .into_iter()
+                .map(|(time, trees)| (time, trees.iter().map(|t| t.stats().load).collect()))
+                .collect()
+        };
+        let updates: Vec<(f64, Vec<f32>)> = loads(Duration::from_secs(1));
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0], (1.0, vec![0.5, 0.4, 0.4]));
+        assert_eq!(updates[1].0, 2.0);
+        assert_eq!(updates[1].1[0], 0.3);
+        assert!(updates[1].1[1].is_nan());
+        assert!(updates[1].1[2].is_nan());
+        // the first update is sent right away, the second would be due after another tick
+        assert_eq!(
+            loads(Duration::from_secs(2)),
+            vec![(1.0, vec![0.5, 0.4, 0.4])]
+        );
+    }
+
+    /// An in-memory process table, in which processes use CPU time only when told to.
+    ///
+    /// Like in Linux, the children of an exiting process are reparented to init, and the CPU time
+    /// of a process, including that of its waited-for descendants, is added to its parent's
+    /// when it is reaped.
+    struct Synthetic {
+        /// The time in ticks
+        ticks: u64,
+        /// The ticks passing between snapshots
+        period: u64,
+        processes: BTreeMap<i32, ProcessInfo>,
+    }
+
+    impl Synthetic {
+        /// A table with just init, PID 1.
+        fn new() -> Self {
+            let mut synthetic = Self {
+                ticks: 0,
+                period: 100,
+                processes: BTreeMap::new(),
+            };
+            synthetic.spawn(1, 0);
+            synthetic
+        }
+
+        fn spawn(&mut self, pid: i32, ppid: i32) {
+            let info = ProcessInfo {
+                start_time: self.ticks,
+                ..fixture::process(pid, ppid, "sh")
+            };
+            self.processes.insert(pid, info);
+        }
+
+        fn rename(&mut self, pid: i32, comm: &str) {
+            self.processes.get_mut(&pid).unwrap().comm = comm.to_owned();
+        }
+
+        fn run(&mut self, pid: i32, ticks: u64) {
+            self.processes.get_mut(&pid).unwrap().self_ticks += ticks;
+        }
+
+        /// Make `pid` a zombie, reparenting its children to init.
+        fn exit(&mut self, pid: i32) {
+            for info in self.processes.values_mut() {
+                if info.ppid == pid {
+                    info.ppid = 1;
+                }
+            }
+        }
+
+        fn reap(&mut self, pid: i32) {
+            let zombie = self.processes.remove(&pid).unwrap();
+            let parent = self.processes.get_mut(&zombie.ppid).unwrap();
+            parent.children_ticks += zombie.self_ticks + zombie.children_ticks;
+        }
+    }
+
+    impl SnapshotSource for Synthetic {
+        /// Take a snapshot after `period` ticks, 100 unless changed, have passed since the
+        /// previous one.
+        fn snapshot(&mut self) -> Option<Result<Snapshot, SnapshotError>> {
+            self.ticks += self.period;
+            Some(Ok(Snapshot {
+                time: self.ticks as f64,
+                uptime: self.ticks,
+                processes: self.processes.values().cloned().collect(),
+            }))
+        }
+    }
+
+    /// Sample the loads of process trees after another 100 ticks.
+    fn sample_loads(sampler: &mut Sampler, processes: &mut Synthetic) -> BTreeMap<i32, f32> {
+        let tick = sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap();
+        let tick = tick.expect("not the first sample");
+        tick.stats
+            .iter()
+            .map(|(pid, (_, stats))| (*pid, stats.load))
+            .collect()
+    }
+
+    #[test]
+    fn accounts_forks_and_exits() {
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        processes.run(10, 1000);
+        let mut sampler = Sampler::new(100, false);
+        assert!(sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap()
+            .is_none());
+        // 11 forked by 10
+        processes.spawn(11, 10);
+        processes.run(10, 10);
+        processes.run(11, 30);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.4), (10, 0.4), (11, 0.3)]));
+        // 12 forked, exited and reaped between samples
+        processes.spawn(12, 10);
+        processes.run(12, 20);
+        processes.exit(12);
+        processes.reap(12);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.2), (10, 0.2), (11, 0.0)]));
+        // 11, which existed at the previous sample, exited and was reaped
+        processes.run(11, 10);
+        processes.exit(11);
+        processes.reap(11);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.1), (10, 0.1)]));
+        // 10 is left with no recent children and its total time does not count
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.0), (10, 0.0)]));
+    }
+
+    #[test]
+    fn accounts_zombies() {
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        processes.spawn(11, 10);
+        processes.run(11, 500);
+        let mut sampler = Sampler::new(100, false);
+        sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap();
+        processes.run(11, 10);
+        processes.exit(11);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.1), (10, 0.1), (11, 0.1)]));
+        // the zombie's time is added to its parent's, but it was already accounted for
+        processes.reap(11);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.0), (10, 0.0)]));
+    }
+
+    #[test]
+    fn counts_hidden_processes() {
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        processes.spawn(11, 10);
+        processes.spawn(12, 1);
+        processes.processes.get_mut(&11).unwrap().hidden_children = 2;
+        processes.processes.get_mut(&12).unwrap().hidden_children = 1;
+        let mut sampler = Sampler::new(100, false);
+        let tick = sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap();
+        assert!(tick.is_none());
+        let tick = sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap();
+        let hidden: BTreeMap<_, _> = tick
+            .expect("not the first sample")
+            .stats
+            .iter()
+            .map(|(pid, (_, stats))| (*pid, stats.hidden))
+            .collect();
+        assert_eq!(
+            hidden,
+            BTreeMap::from([(1, 3.0), (10, 2.0), (11, 2.0), (12, 1.0)])
+        );
+    }
+
+    #[test]
+    fn accounts_reparenting() {
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        processes.spawn(11, 10);
+        processes.spawn(12, 11);
+        // 12 has waited for a child of its own
+        processes.spawn(13, 12);
+        processes.run(13, 700);
+        processes.exit(13);
+        processes.reap(13);
+        processes.spawn(14, 12);
+        processes.run(14, 50);
+        processes.run(11, 300);
+        let mut sampler = Sampler::new(100, false);
+        sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap();
+        // 11 exits, so 12 is reparented to init, and 11 is reaped by 10
+        processes.run(11, 5);
+        processes.exit(11);
+        processes.reap(11);
+        processes.run(12, 10);
+        // and then 14 exits and is reaped by 12, which is no longer in the tree of 10
+        processes.run(14, 3);
+        processes.exit(14);
+        processes.reap(14);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.18), (10, 0.05), (12, 0.13)]));
+        // 12 exits and is reaped by init
+        processes.run(12, 20);
+        processes.exit(12);
+        processes.reap(12);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert_eq!(loads, BTreeMap::from([(1, 0.2), (10, 0.0)]));
+    }
+
+    #[test]
+    fn accounts_reaping_after_reparenting() {
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        processes.spawn(11, 10);
+        processes.spawn(12, 11);
+        processes.run(12, 500);
+        let mut sampler = Sampler::new(100, false);
+        sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap();
+        // 11 exits and is reaped by 10, so 12 is reparented to init, which reaps it after it exits
+        processes.exit(11);
+        processes.reap(11);
+        processes.run(12, 10);
+        processes.exit(12);
+        processes.reap(12);
+        processes.run(10, 30);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        // 10 has not reaped the time of 12, so it is not taken from what 10 used itself
+        assert_eq!(loads, BTreeMap::from([(1, 0.4), (10, 0.3)]));
+    }
+
+    #[test]
+    fn breaks_parent_cycles() {
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        processes.spawn(11, 10);
+        let mut sampler = Sampler::new(100, false);
+        sampler
+            .sample(&processes.snapshot().unwrap().unwrap())
+            .unwrap();
+        // 11 died and a new 10 was forked by another 11 while the processes were being read
+        processes.processes.get_mut(&10).unwrap().ppid = 11;
+        processes.run(10, 10);
+        processes.run(11, 20);
+        let loads = sample_loads(&mut sampler, &mut processes);
+        assert!(
+            loads == BTreeMap::from([(1, 0.0), (10, 0.3), (11, 0.2)])
+                || loads == BTreeMap::from([(1, 0.0), (10, 0.1), (11, 0.3)]),
+            "{loads:?}"
+        );
+    }
+
+    #[test]
+    fn samples_with_hidden_init() {
+        let root = std::env::temp_dir().join(format!(
+            "pidtree_mon-test-{}-hidden-init",
+            std::process::id()
+        ));
+        let tps = procfs::ticks_per_second();
+        let process = |pid, ppid, self_ticks| ProcessInfo {
+            self_ticks,
+            ..fixture::process(pid, ppid, "sh")
+        };
+        let mut sampler = Sampler::new(tps, false);
+        let mut sample = |seconds: u64| {
+            let ticks = seconds * tps / 4;
+            let processes = [
+                process(1, 0, 0),
+                process(20, 1, ticks),
+                process(21, 20, ticks),
+            ];
+            fixture::write(&root, 10 * tps + seconds * tps, &processes);
+            // init belongs to root and is hidden from the user of 20 and 21
+            fixture::restrict(&root, 20, "invisible");
+            std::fs::remove_dir_all(root.join("1")).unwrap();
+            let snapshot = Snapshot::capture(&root, false, false).unwrap();
+            sampler.sample(&snapshot).unwrap()
+        };
+        assert!(sample(0).is_none());
+        let tick = sample(1).expect("not the first sample");
+        let stats: BTreeMap<_, _> = tick
+            .stats
+            .iter()
+            .map(|(pid, (_, stats))| (*pid, (stats.load, stats.procs)))
+            .collect();
+        assert_eq!(stats, BTreeMap::from([(20, (0.5, 2.0)), (21, (0.25, 1.0))]));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Snapshots of consecutive fixtures.
+    struct Fixtures(std::vec::IntoIter<Procfs>);
+
+    impl SnapshotSource for Fixtures {
+        fn snapshot(&mut self) -> Option<Result<Snapshot, SnapshotError>> {
+            self.0.next()?.snapshot()
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_clients_end_to_end() {
+        use clap::Parser as _;
+        use tokio::net::UnixListener;
+
+        use crate::{client, config::Config};
+
+        // larger than any possible PID, so that the client does not find them on the live system
+        const ROOT: i32 = 5_000_001;
+        const CHILD: i32 = 5_000_002;
+        let dir = std::env::temp_dir().join(format!("pidtree_mon-test-{}-e2e", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // each second, the root and its child each use a quarter of a core and the child reads 1K
+        let fixtures = (0..4).map(|i| {
+            let root = dir.join(format!("proc{i}"));
+            let process = |pid, ppid, start_time, self_ticks, read_bytes| ProcessInfo {
+                start_time,
+                self_ticks,
+                read_bytes,
+                ..fixture::process(pid, ppid, "sh")
+            };
+            let processes = [
+                process(1, 0, 0, 0, 0),
+                process(ROOT, 1, 500, 25 * i, 0),
+                process(CHILD, ROOT, 600, 25 * i, 1024 * i),
+            ];
+            let tps = procfs::ticks_per_second();
+            fixture::write(&root, 1000 + tps * i, &processes);
+            Procfs {
+                root,
+                measure_pss: false,
+                read_commands: false,
+            }
+        });
+        let worker = test_worker(Fixtures(fixtures.collect::<Vec<_>>().into_iter())).await;
+        let socket = dir.join("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(Arc::clone(&worker).handle_client(stream));
+            }
+        });
+
+        let mut stream = TokioUnixStream::connect(&socket).await.unwrap();
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+        client::handshake(&mut reader, &mut writer, &[])
+            .await
+            .unwrap();
+        let roots = [(ROOT, Some(500)), (CHILD, None), (CHILD, Some(1))]
+            .map(|(pid, start_time)| Root { pid, start_time })
+            .to_vec();
+        let interval = Duration::from_millis(50);
+        Request::Monitor {
+            roots,
+            selectors: vec![],
+            interval,
+            wait_full: false,
+        }
+        .write_to(&mut writer)
+        .await
+        .unwrap();
+        let mut updates = 0;
+        // the updates end with an error when there are no more snapshots
+        loop {
+            match Response::read_from(&mut reader).await.unwrap() {
+                Response::Update(trees) => {
+                    let [TreeState::Alive(root), TreeState::Alive(child), TreeState::Gone] =
+                        trees.as_slice()
+                    else {
+                        panic!("unexpected trees: {trees:?}");
+                    };
+                    assert_eq!((root.load, root.io_read, root.procs), (0.5, 1024.0, 2.0));
+                    assert_eq!(
+                        (child.load, child.io_read, child.procs),
+                        (0.25, 1024.0, 1.0)
+                    );
+                    updates += 1;
+                }
+                Response::Error(e) => {
+                    assert_eq!(e, "sampling process trees failed");
+                    break;
+                }
+                response => panic!("unexpected response: {response:?}"),
+            }
+        }
+        assert!(updates > 0);
+
+        // the client's own output
+        let log_file = dir.join("log.csv");
+        let config = Config::try_parse_from([
+            "pidtree_mon",
+            "--once",
+            "--log-file",
+            log_file.to_str().unwrap(),
+            "--log-metrics=io_read",
+            &ROOT.to_string(),
+            &CHILD.to_string(),
+        ])
+        .unwrap();
+        let stream = TokioUnixStream::connect(&socket).await.unwrap();
+        let outcome = client::run(stream, &config, false).await.unwrap();
+        assert_eq!(outcome, client::Outcome::Finished);
+        let log = std::fs::read_to_string(&log_file).unwrap();
+        let rows: Vec<_> = log
+            .lines()
+            .map(|row| row.split_once(',').unwrap().1)
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                "load_5000001,load_5000002,load_sum,io_read_5000001,io_read_5000002,io_read_sum",
+                "0.5,0.25,0.75,1024,1024,2048",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Snapshots and failures in a predetermined order.
+    struct Scripted(std::vec::IntoIter<Result<Snapshot, SnapshotError>>);
+
+    impl SnapshotSource for Scripted {
+        fn snapshot(&mut self) -> Option<Result<Snapshot, SnapshotError>> {
+            self.0.next()
+        }
+    }
+
+    /// Create a worker sampling every 50 ms snapshots taken from `source`.
+    async fn test_worker(source: impl SnapshotSource) -> Arc<Worker> {
+        let worker = Worker::with_source(test_settings(), None, source)
+            .await
+            .unwrap();
+        Arc::new(worker)
+    }
+
+    /// The settings of a worker sampling every 50 ms without any optional features.
+    fn test_settings() -> Settings {
+        Settings {
+            update_interval: Duration::from_millis(50),
+            measure_pss: false,
+            idle_timeout: None,
+            record: None,
+        }
+    }
+
+    /// A request to monitor the tree of `pid` and `selectors` every 50 ms.
+    fn monitor(pid: i32, selectors: Vec<Selector>) -> Request {
+        Request::Monitor {
+            roots: vec![Root {
+                pid,
+                start_time: None,
+            }],
+            selectors,
+            interval: Duration::from_millis(50),
+            wait_full: false,
+        }
+    }
+
+    /// Send `request` to `worker` and read the updates until it sends an error, returning the
+    /// loads of each update sent before and the error.
+    async fn monitor_until_error(worker: Arc<Worker>, request: Request) -> (Vec<Vec<f32>>, String) {
+        let (mut stream, worker_stream) = TokioUnixStream::pair().unwrap();
+        tokio::spawn(worker.handle_client(worker_stream));
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+        crate::client::handshake(&mut reader, &mut writer, &[])
+            .await
+            .unwrap();
+        request.write_to(&mut writer).await.unwrap();
+        let mut loads = vec![];
+        loop {
+            match Response::read_from(&mut reader).await.unwrap() {
+                Response::Update(trees) => {
+                    loads.push(trees.iter().map(|tree| tree.stats().load).collect())
+                }
+                Response::Error(e) => return (loads, e),
+                response => panic!("unexpected response: {response:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_transient_failures() {
+        let transient = || SnapshotError::Transient("cannot read processes: busy".to_owned());
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        let mut script = vec![processes.snapshot().unwrap()];
+        processes.run(10, 50);
+        // the skipped snapshot still advances the clock
+        processes.snapshot();
+        script.push(Err(transient()));
+        // and so does one taken before the previous one
+        script.push(Ok(Snapshot {
+            time: 0.0,
+            uptime: 50,
+            processes: vec![],
+        }));
+        script.push(processes.snapshot().unwrap());
+        script.push(Err(SnapshotError::Unavailable(
+            "cannot read processes: gone".to_owned(),
+        )));
+        let (loads, error) = monitor_until_error(
+            test_worker(Scripted(script.into_iter())).await,
+            monitor(10, vec![]),
+        )
+        .await;
+        assert_eq!(loads, vec![vec![0.25]]);
+        assert_eq!(
+            error,
+            "sampling process trees failed: cannot read processes: gone"
+        );
+
+        // too many consecutive transient failures are fatal
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        let mut script = vec![processes.snapshot().unwrap(), processes.snapshot().unwrap()];
+        script.extend((0..=MAX_SKIPPED_SAMPLES).map(|_| Err(transient())));
+        let (loads, error) = monitor_until_error(
+            test_worker(Scripted(script.into_iter())).await,
+            monitor(10, vec![]),
+        )
+        .await;
+        assert_eq!(loads, vec![vec![0.0]]);
+        assert_eq!(
+            error,
+            "sampling process trees failed: cannot read processes: busy"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_to_create_recording() {
+        let settings = Settings {
+            record: Some("/nonexistent/pidtree_mon.rec".into()),
+            ..test_settings()
+        };
+        let Err(error) = Worker::with_source(settings, None, Synthetic::new()).await else {
+            panic!("recording created");
+        };
+        assert!(
+            error.starts_with("cannot create recording /nonexistent/pidtree_mon.rec: "),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_daemon_lacking_capability() {
+        use clap::Parser as _;
+
+        use crate::{client, config::Config};
+
+        let mut processes = Synthetic::new();
+        let script = vec![processes.snapshot().unwrap()];
+        let worker = test_worker(Scripted(script.into_iter())).await;
+        let (stream, worker_stream) = TokioUnixStream::pair().unwrap();
+        tokio::spawn(worker.handle_client(worker_stream));
+        let config = Config::try_parse_from(["pidtree_mon", "-f", "pss", "1"]).unwrap();
+        // the daemon is of the same version, so it must not be replaced
+        match client::run(stream, &config, true).await {
+            Err(client::ClientError::Other(e)) => assert!(e.contains("lacks capability `pss`")),
+            res => panic!("unexpected result: {res:?}"),
+        }
+    }
+
+    /// A change to a synthetic process table.
+    type Step = Box<dyn FnOnce(&mut Synthetic) + Send>;
+
+    /// A synthetic process table, changed by each step of a script only while a client is
+    /// connected, so that tests do not depend on how soon clients connect.
+    struct Gated {
+        processes: Synthetic,
+        script: std::vec::IntoIter<Step>,
+        /// The number of connected clients of the worker, set once it is created
+        clients: Arc<std::sync::OnceLock<Arc<AtomicU32>>>,
+    }
+
+    impl SnapshotSource for Gated {
+        /// Take a snapshot after the next step if a client is connected, until the script ends.
+        fn snapshot(&mut self) -> Option<Result<Snapshot, SnapshotError>> {
+            if self
+                .clients
+                .get()
+                .is_some_and(|c| c.load(Ordering::Relaxed) > 0)
+            {
+                (self.script.next()?)(&mut self.processes);
+            }
+            self.processes.snapshot()
+        }
+    }
+
+    /// Create a worker sampling `processes` changed by `script`, see [Gated].
+    async fn gated_worker(processes: Synthetic, script: Vec<Step>) -> Arc<Worker> {
+        let clients = Arc::new(std::sync::OnceLock::new());
+        let source = Gated {
+            processes,
+            script: script.into_iter(),
+            clients: Arc::clone(&clients),
+        };
+        let worker = test_worker(source).await;
+        let _ = clients.set(Arc::clone(&worker.clients));
+        worker
+    }
+
+    #[tokio::test]
+    async fn exits_when_all_trees_are_gone() {
+        use clap::Parser as _;
+
+        use crate::{client, config::Config};
+
+        // larger than any possible PID, so that the client does not find it on the live system
+        const ROOT: i32 = 5_000_001;
+        let mut processes = Synthetic::new();
+        processes.spawn(ROOT, 1);
+        let script: Vec<Step> = vec![
+            Box::new(|p| p.run(ROOT, 10)),
+            Box::new(|p| {
+                p.exit(ROOT);
+                p.reap(ROOT);
+            }),
+        ];
+        let worker = gated_worker(processes, script).await;
+        let (stream, worker_stream) = TokioUnixStream::pair().unwrap();
+        tokio::spawn(worker.handle_client(worker_stream));
+        let root = ROOT.to_string();
+        let config =
+            Config::try_parse_from(["pidtree_mon", "-i", "0.05", "--exit-when-gone", &root])
+                .unwrap();
+        let outcome = client::run(stream, &config, false).await.unwrap();
+        assert_eq!(outcome, client::Outcome::AllGone);
+    }
+
+    #[tokio::test]
+    async fn exits_when_selected_trees_are_gone() {
+        use clap::Parser as _;
+
+        use crate::{client, config::Config};
+
+        let dir = std::env::temp_dir().join(format!(
+            "pidtree_mon-test-{}-exit-when-gone",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        // nothing matches at first
+        let script: Vec<Step> = vec![
+            Box::new(|_| {}),
+            Box::new(|p| {
+                p.spawn(20, 1);
+                p.rename(20, "cc");
+            }),
+            Box::new(|p| p.run(20, 10)),
+            Box::new(|p| {
+                p.exit(20);
+                p.reap(20);
+            }),
+        ];
+        let worker = gated_worker(Synthetic::new(), script).await;
+        let (stream, worker_stream) = TokioUnixStream::pair().unwrap();
+        tokio::spawn(worker.handle_client(worker_stream));
+        let log_file = dir.join("log.csv");
+        let config = Config::try_parse_from([
+            "pidtree_mon",
+            "-i",
+            "0.05",
+            "--exit-when-gone",
+            "--log-file",
+            log_file.to_str().unwrap(),
+            "--comm",
+            "cc",
+        ])
+        .unwrap();
+        let outcome = client::run(stream, &config, false).await.unwrap();
+        assert_eq!(outcome, client::Outcome::AllGone);
+        let log = std::fs::read_to_string(&log_file).unwrap();
+        let loads: Vec<_> = log
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').nth(1).unwrap().to_owned())
+            .collect();
+        assert_eq!(loads, vec!["", "0", "0.1", ""]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_for_full_interval() {
+        // snapshots are taken every 50 ms, as often as the worker samples them
+        let setup = |processes: &mut Synthetic| -> Vec<Step> {
+            processes.period = 5;
+            processes.spawn(10, 1);
+            [1, 5, 0]
+                .into_iter()
+                .map(|ticks| Box::new(move |p: &mut Synthetic| p.run(10, ticks)) as Step)
+                .collect()
+        };
+        let request = |wait_full| Request::Monitor {
+            roots: vec![Root {
+                pid: 10,
+                start_time: None,
+            }],
+            selectors: vec![],
+            interval: Duration::from_millis(150),
+            wait_full,
+        };
+        // Up to one idle tick sampled before the client connected may be sent right away, but it
+        // is not a measurement over the whole interval.
+        let mut processes = Synthetic::new();
+        let script = setup(&mut processes);
+        let worker = gated_worker(processes, script).await;
+        let (loads, _) = monitor_until_error(worker, request(false)).await;
+        let first = format!("{:.1}", loads[0][0]);
+        assert!(first == "0.0" || first == "0.2", "{loads:?}");
+        // Waiting for the whole interval, it spans either that tick and the first two of the
+        // script or the first three of the script, which both average to the same load.
+        let mut processes = Synthetic::new();
+        let script = setup(&mut processes);
+        let worker = gated_worker(processes, script).await;
+        let (loads, _) = monitor_until_error(worker, request(true)).await;
+        assert_eq!(format!("{:.1}", loads[0][0]), "0.4");
+    }
+
+    #[tokio::test]
+    async fn resolves_selectors() {
+        let mut processes = Synthetic::new();
+        processes.spawn(10, 1);
+        processes.rename(10, "make");
+        processes.spawn(11, 10);
+        processes.rename(11, "cc");
+        // the second process is spawned once the client is connected
+        let script = (0..20)
+            .map(|i| {
+                Box::new(move |p: &mut Synthetic| {
+                    p.run(11, 20);
+                    if i == 10 {
+                        p.spawn(12, 1);
+                        p.rename(12, "cc");
+                    } else if i > 10 {
+                        p.run(12, 30);
+                    }
+                }) as Step
+            })
+            .collect();
+        let selectors = ["cc", "make", "firefox"].map(|comm| Selector::Comm(comm.to_owned()));
+        let (loads, error) = monitor_until_error(
+            gated_worker(processes, script).await,
+            monitor(11, selectors.to_vec()),
+        )
+        .await;
+        assert_eq!(error, "sampling process trees failed");
+        // the selected trees are gone (unknown) if nothing matches
+        let mut distinct: Vec<Vec<_>> = loads
+            .iter()
+            .map(|loads| loads.iter().map(|load| format!("{load}")).collect())
+            .collect();
+        distinct.dedup();
+        assert_eq!(
+            distinct,
+            vec![
+                vec!["0.2", "0.2", "0.2", "NaN"],
+                vec!["0.2", "0.5", "0.2", "NaN"]
+            ]
+        );
+    }
+
+    #[test]
+    fn samples_at_fastest_interval() {
+        let default = Duration::from_secs(1);
+        let mut intervals = Intervals::new();
+        assert_eq!(sampling_interval(&intervals, default), default);
+        intervals.insert(Duration::from_secs(5), 1);
+        intervals.insert(Duration::from_millis(250), 2);
+        assert_eq!(
+            sampling_interval(&intervals, default),
+            Duration::from_millis(250)
+        );
+        intervals.insert(Duration::from_millis(1), 1);
+        assert_eq!(sampling_interval(&intervals, default), MIN_INTERVAL);
+    }
+}